@@ -1,8 +1,18 @@
+use std::borrow::Borrow;
 use std::cell::{Ref, RefCell};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::{Add, Bound};
 use std::rc::{Rc, Weak};
-use std::cmp::min;
+use std::str::FromStr;
+use std::cmp::{max, min, Reverse};
+
+#[cfg(feature = "petgraph")]
+use petgraph::graph::{DiGraph, NodeIndex};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 
 /// TODO:
@@ -30,8 +40,8 @@ where
     parent: RefCell<Option<Weak<Node<K, T>>>>,
     // The value stored in this node
     value: RefCell<T>,
-    // Unique key to identify the node
-    key: K,
+    // Unique key to identify the node (a RefCell so the tree can rename it)
+    key: RefCell<K>,
 }
 
 impl<K, T> Node<K, T>
@@ -45,12 +55,16 @@ where
             index: RefCell::new(usize::default()),
             parent: RefCell::new(None),
             value: RefCell::new(value),
-            key,
+            key: RefCell::new(key),
         })
     }
 
+    // The four methods below are the raw linking primitives. They only keep the primary index
+    // consistent when driven by the tree, so they are crate-private; use the tree-level
+    // operations instead (`insert`, `remove`, `remove_subtree`, `move_node`, ...)
+
     // Detach a specific child from the node
-    pub fn abandon(&self, child: &Rc<Self>) {
+    pub(crate) fn abandon(&self, child: &Rc<Self>) {
         let index = *child.index.borrow();
         *child.parent.borrow_mut() = None;
         self.children.borrow_mut().swap_remove(index);
@@ -64,12 +78,16 @@ where
     }
 
     // Adopt a new child node under the current node
-    pub fn adopt(self: &Rc<Self>, child: &Rc<Self>, index: &mut HashMap<K, Rc<Node<K, T>>>) {
+    pub(crate) fn adopt(self: &Rc<Self>, child: &Rc<Self>, index: &mut HashMap<K, Rc<Node<K, T>>>) {
         child.attach(self, index); // Pass the index map to attach
     }
 
     // Attach this node to a new parent
-    pub fn attach(self: &Rc<Self>, parent: &Rc<Self>, index: &mut HashMap<K, Rc<Node<K, T>>>) {
+    pub(crate) fn attach(
+        self: &Rc<Self>,
+        parent: &Rc<Self>,
+        index: &mut HashMap<K, Rc<Node<K, T>>>,
+    ) {
         self.detach(index); // Pass the index to detach
         *self.index.borrow_mut() = parent.children.borrow().len();
         *self.parent.borrow_mut() = Some(Rc::downgrade(parent));
@@ -77,7 +95,7 @@ where
     }
 
     // Detach this node from its current parent
-    pub fn detach(self: &Rc<Self>, index: &mut HashMap<K, Rc<Node<K, T>>>) {
+    pub(crate) fn detach(self: &Rc<Self>, index: &mut HashMap<K, Rc<Node<K, T>>>) {
         if let Some(parent) = self.parent() {
             parent.abandon(self);
         }
@@ -90,14 +108,14 @@ where
             for child in node.children.borrow().iter() {
                 remove_descendants(child, index);
             }
-            index.remove(&node.key);
+            index.remove(&*node.key());
         }
 
         remove_descendants(self, index);
     }
 
     // Get a reference to the children of this node
-    pub fn children(&self) -> Ref<Vec<Rc<Node<K, T>>>> {
+    pub fn children(&self) -> Ref<'_, Vec<Rc<Node<K, T>>>> {
         self.children.borrow()
     }
 
@@ -117,7 +135,7 @@ where
     }
 
     // Get the value stored in this node
-    pub fn value(&self) -> Ref<T> {
+    pub fn value(&self) -> Ref<'_, T> {
         self.value.borrow()
     }
 
@@ -126,21 +144,142 @@ where
         *self.value.borrow_mut() = value;
     }
 
-    // Get the unique key of this node
-    pub fn key(&self) -> &K {
-        &self.key
+    // Get the unique key of this node. Keys can be renamed through
+    // `MultiIndexedTree::rename_key`, so this returns a borrow guard rather than a plain reference
+    pub fn key(&self) -> Ref<'_, K> {
+        self.key.borrow()
+    }
+
+    // Insert a child at `position` (clamped to the end), keeping sibling order and indices intact
+    fn insert_child(self: &Rc<Self>, child: &Rc<Self>, position: usize) {
+        let position = min(position, self.children.borrow().len());
+        *child.parent.borrow_mut() = Some(Rc::downgrade(self));
+        self.children.borrow_mut().insert(position, child.clone());
+        self.reindex_children_from(position);
+    }
+
+    // Unlink a child without disturbing the order of its remaining siblings
+    fn remove_child(&self, child: &Rc<Self>) {
+        let position = *child.index.borrow();
+        *child.parent.borrow_mut() = None;
+        self.children.borrow_mut().remove(position);
+        self.reindex_children_from(position);
+    }
+
+    // Rewrite the `index` field of every child from `start` onwards to match its position
+    fn reindex_children_from(&self, start: usize) {
+        for (position, child) in self.children.borrow().iter().enumerate().skip(start) {
+            *child.index.borrow_mut() = position;
+        }
+    }
+
+    // Get the number of edges between this node and the root
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut current = self.parent();
+        while let Some(node) = current {
+            depth += 1;
+            current = node.parent();
+        }
+        depth
+    }
+}
+
+// Errors returned by tree operations
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeError<K> {
+    // No node exists with the given key
+    KeyNotFound(K),
+    // A node with the given key already exists
+    DuplicateKey(K),
+    // The operation cannot be applied to the root node
+    RootNode(K),
+    // The node with the given key already has the maximum number of children
+    ChildLimitReached(K),
+    // The operation would make the node with the given key its own ancestor
+    CycleDetected(K),
+    // The node's links or index entries disagree with the tree's actual structure
+    Inconsistent(K),
+    // The input describes more than one root; the key is that of an extra root
+    MultipleRoots(K),
+    // The input gives the node with the given key more than one parent
+    MultipleParents(K),
+    // The input could not be parsed; the message says what was wrong
+    Syntax(String),
+    // Adding the node with the given key (and its subtree) would exceed the tree's node
+    // capacity (see `MultiIndexedTree::with_capacity_eviction`)
+    CapacityExceeded(K),
+}
+
+impl<K: Debug> Display for TreeError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::KeyNotFound(key) => write!(f, "key not found: {:?}", key),
+            TreeError::DuplicateKey(key) => write!(f, "key already exists: {:?}", key),
+            TreeError::RootNode(key) => write!(f, "operation not allowed on root node: {:?}", key),
+            TreeError::ChildLimitReached(key) => {
+                write!(
+                    f,
+                    "node has reached the maximum number of children: {:?}",
+                    key
+                )
+            }
+            TreeError::CycleDetected(key) => write!(f, "operation would create a cycle: {:?}", key),
+            TreeError::MultipleRoots(key) => write!(f, "more than one root: {:?}", key),
+            TreeError::MultipleParents(key) => write!(f, "more than one parent: {:?}", key),
+            TreeError::Syntax(message) => write!(f, "syntax error: {}", message),
+            TreeError::CapacityExceeded(key) => write!(f, "tree capacity exceeded by: {:?}", key),
+            TreeError::Inconsistent(key) => write!(f, "inconsistent tree structure at: {:?}", key),
+        }
     }
 }
 
+impl<K: Debug> std::error::Error for TreeError<K> {}
+
+// A tree whose values are stored behind `Rc`. Every operation that needs `T: Clone` (merging,
+// extracting induced subtrees, serializing, ...) then only bumps a reference count instead of
+// deep-copying the payload, which matters when each node holds a large struct. The trade-off
+// is that cloned values are shared: they can no longer be mutated through `&mut`, so updates
+// replace the whole `Rc` (e.g. via `Node::set_value`) or go through interior mutability in `V`
+pub type SharedValueTree<K, V> = MultiIndexedTree<K, Rc<V>>;
+
+// A node in the left-child/right-sibling binary encoding of a tree (see
+// `MultiIndexedTree::to_binary`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BinaryNode<K, T> {
+    pub key: K,
+    pub value: T,
+    pub left: Option<Box<BinaryNode<K, T>>>, // First child in the original tree
+    pub right: Option<Box<BinaryNode<K, T>>>, // Next sibling in the original tree
+}
+
+// The two halves produced by `MultiIndexedTree::split_at`: `(detached, remaining)`
+pub type SplitTrees<K, T> = (MultiIndexedTree<K, T>, MultiIndexedTree<K, T>);
+
+// Sorted mirror of the primary index, used for range queries
+type OrderedIndex<K, T> = BTreeMap<K, Rc<Node<K, T>>>;
+
+// Access-order bookkeeping for capacity-bounded trees (see
+// `MultiIndexedTree::with_capacity_eviction`)
+#[derive(Clone, Debug)]
+struct Eviction<K> {
+    max_nodes: usize,
+    clock: u64,                   // Incremented on every access
+    last_access: HashMap<K, u64>, // Clock value at each key's most recent access
+}
+
 // Multi-Indexed Tree structure
 #[derive(Debug)]
 pub struct MultiIndexedTree<K, T>
 where
     K: Eq + Hash + Ord,
 {
-    root: Rc<Node<K, T>>,
+    root: RefCell<Rc<Node<K, T>>>,
     index: RefCell<HashMap<K, Rc<Node<K, T>>>>,  // Primary index for quick lookup by key
-    secondary_index: RefCell<HashMap<String, Vec<K>>>, // Secondary index for grouping by tags
+    secondary_index: RefCell<HashMap<String, Vec<K>>>, // Secondary index for grouping by tags (insertion-ordered)
+    max_children: Option<usize>, // Upper bound on children per node, if any
+    ordered_index: RefCell<Option<OrderedIndex<K, T>>>, // Optional sorted index for range queries
+    eviction: RefCell<Option<Eviction<K>>>, // Optional LRU eviction of leaves once the tree is full
 }
 
 impl<K, T> MultiIndexedTree<K, T>
@@ -155,10 +294,278 @@ where
         index.insert(root_key, root.clone());
 
         Self {
-            root,
+            root: RefCell::new(root),
+            index: RefCell::new(index),
+            secondary_index: RefCell::new(HashMap::new()),
+            max_children: None,
+            ordered_index: RefCell::new(None),
+            eviction: RefCell::new(None),
+        }
+    }
+
+    // Get the current root node
+    pub fn root(&self) -> Rc<Node<K, T>> {
+        self.root.borrow().clone()
+    }
+
+    // Create a new tree where no node may have more than `max` children
+    pub fn with_max_children(root_key: K, root_value: T, max: usize) -> Self {
+        let mut tree = Self::new(root_key, root_value);
+        tree.max_children = Some(max);
+        tree
+    }
+
+    // Create a new tree whose primary index has room for at least `capacity` nodes
+    pub fn with_capacity(root_key: K, root_value: T, capacity: usize) -> Self {
+        let tree = Self::new(root_key, root_value);
+        tree.reserve(capacity.saturating_sub(1));
+        tree
+    }
+
+    // Create a bounded tree that holds at most `max_nodes` nodes. Inserting into a full tree
+    // first evicts the least-recently-accessed leaf, where a lookup through `find` (and so
+    // `update`) counts as an access and new nodes start out as just accessed. The root and the insert's parent are never
+    // evicted; if no other leaf exists the insert fails. Each eviction scans all nodes.
+    // Grafting whole subtrees (`attach_subtree`, `replace_subtree`) does not evict: it fails
+    // with `CapacityExceeded` if the result would not fit
+    pub fn with_capacity_eviction(root_key: K, root_value: T, max_nodes: usize) -> Self {
+        let tree = Self::new(root_key.clone(), root_value);
+        *tree.eviction.borrow_mut() = Some(Eviction {
+            max_nodes,
+            clock: 0,
+            last_access: HashMap::new(),
+        });
+        tree.touch(&root_key);
+        tree
+    }
+
+    // Record an access to `key` for LRU eviction, if enabled
+    fn touch(&self, key: &K) {
+        if let Some(eviction) = self.eviction.borrow_mut().as_mut() {
+            eviction.clock += 1;
+            let clock = eviction.clock;
+            match eviction.last_access.get_mut(key) {
+                Some(last_access) => *last_access = clock,
+                None => {
+                    eviction.last_access.insert(key.clone(), clock);
+                }
+            }
+        }
+    }
+
+    // Evict leaves, least recently accessed first, until there is room for one more node.
+    // `keep` is never evicted. Returns false if the tree is still full with nothing left to evict
+    fn make_room(&self, keep: &Rc<Node<K, T>>) -> bool {
+        let mut eviction = self.eviction.borrow_mut();
+        let Some(eviction) = eviction.as_mut() else {
+            return true;
+        };
+
+        let mut index = self.index.borrow_mut();
+        eviction
+            .last_access
+            .retain(|key, _| index.contains_key(key));
+        while index.len() >= eviction.max_nodes {
+            // Ties (only possible between never-accessed nodes) go to the smallest key
+            let victim = index
+                .values()
+                .filter(|node| node.is_leaf() && !node.is_root() && !Rc::ptr_eq(node, keep))
+                .min_by_key(|node| {
+                    let key = node.key().clone();
+                    (eviction.last_access.get(&key).copied().unwrap_or(0), key)
+                })
+                .cloned();
+            let Some(victim) = victim else {
+                return false;
+            };
+
+            if let Some(parent) = victim.parent() {
+                parent.remove_child(&victim);
+            }
+            self.unindex_ordered(&victim);
+            let key = victim.key().clone();
+            eviction.last_access.remove(&key);
+            index.remove(&key);
+        }
+        true
+    }
+
+    // Reserve room in the primary index for at least `additional` more nodes, avoiding
+    // repeated rehashing during bulk loads
+    pub fn reserve(&self, additional: usize) {
+        self.index.borrow_mut().reserve(additional);
+    }
+
+    // Build a tree from a map of each key to its parent key, as hierarchies are often stored in
+    // databases. `root` must not appear as a child in `parents`; every other key is inserted
+    // after its parent, with siblings in ascending key order, and takes its value from
+    // `values`. Fails if some key's chain of parents loops or ends at a key other than `root`
+    pub fn from_parent_map(
+        parents: &HashMap<K, K>,
+        root: K,
+        values: impl Fn(&K) -> T,
+    ) -> Result<Self, TreeError<K>> {
+        // Explain why `start` cannot hang below `root`: its parent chain either loops or
+        // tops out at some other parentless key
+        let diagnose = |start: &K| {
+            let mut seen = HashSet::new();
+            let mut current = start.clone();
+            loop {
+                if !seen.insert(current.clone()) {
+                    return TreeError::CycleDetected(current);
+                }
+                match parents.get(&current) {
+                    Some(parent) => current = parent.clone(),
+                    None => return TreeError::MultipleRoots(current),
+                }
+            }
+        };
+        if parents.contains_key(&root) {
+            return Err(diagnose(&root));
+        }
+
+        let mut children: HashMap<&K, Vec<&K>> = HashMap::new();
+        for (child, parent) in parents.iter() {
+            children.entry(parent).or_default().push(child);
+        }
+
+        let tree = Self::new(root.clone(), values(&root));
+        let mut queue = VecDeque::from([root]);
+        while let Some(parent) = queue.pop_front() {
+            let mut below = children.remove(&parent).unwrap_or_default();
+            below.sort();
+            for child in below {
+                let _ = tree.insert(&parent, child.clone(), values(child));
+                queue.push_back(child.clone());
+            }
+        }
+
+        // Anything left unreached points at a cycle or a second root
+        match parents
+            .keys()
+            .filter(|key| tree.lookup(key).is_none())
+            .min()
+        {
+            Some(unreached) => Err(diagnose(unreached)),
+            None => Ok(tree),
+        }
+    }
+
+    // Build a tree around a detached node, indexing it and all of its descendants
+    fn from_root(root: Rc<Node<K, T>>) -> Self {
+        let mut index = HashMap::new();
+        let nodes = DepthFirstIterator {
+            stack: vec![root.clone()],
+        };
+        for node in nodes {
+            let key = node.key().clone();
+            index.insert(key, node);
+        }
+
+        Self {
+            root: RefCell::new(root),
             index: RefCell::new(index),
             secondary_index: RefCell::new(HashMap::new()),
+            max_children: None,
+            ordered_index: RefCell::new(None),
+            eviction: RefCell::new(None),
+        }
+    }
+
+    // Maintain a sorted copy of the primary index so that `range` queries no longer have to
+    // scan and sort every key. Costs an extra O(log n) on every insert and removal
+    pub fn enable_ordered_index(&self) {
+        let ordered = self
+            .index
+            .borrow()
+            .iter()
+            .map(|(key, node)| (key.clone(), node.clone()))
+            .collect();
+        *self.ordered_index.borrow_mut() = Some(ordered);
+    }
+
+    // Record a newly indexed node in the ordered index, if enabled
+    fn index_ordered(&self, node: &Rc<Node<K, T>>) {
+        if let Some(ordered) = self.ordered_index.borrow_mut().as_mut() {
+            ordered.insert(node.key().clone(), node.clone());
+        }
+    }
+
+    // Drop a node and all of its descendants from the ordered index, if enabled
+    fn unindex_ordered(&self, node: &Rc<Node<K, T>>) {
+        if let Some(ordered) = self.ordered_index.borrow_mut().as_mut() {
+            let nodes = DepthFirstIterator {
+                stack: vec![node.clone()],
+            };
+            for node in nodes {
+                ordered.remove(&*node.key());
+            }
+        }
+    }
+
+    // Copy the subtree rooted at `node` into a brand new tree with freshly allocated nodes
+    fn clone_subtree(node: &Rc<Node<K, T>>) -> Self {
+        let tree = Self::new(node.key().clone(), node.value().clone());
+        let nodes = ParentIterator {
+            stack: vec![(None, node.clone())],
+        };
+
+        // Pre-order guarantees each parent is copied before its children
+        for (parent, original) in nodes {
+            if let Some(parent) = parent {
+                let _ = tree.insert(
+                    &parent.key(),
+                    original.key().clone(),
+                    original.value().clone(),
+                );
+            }
+        }
+        tree
+    }
+
+    // Copy the whole tree, including its secondary index and settings, into independent nodes
+    pub fn deep_clone(&self) -> Self {
+        let mut tree = Self::clone_subtree(&self.root());
+        tree.max_children = self.max_children;
+        *tree.secondary_index.borrow_mut() = self.secondary_index.borrow().clone();
+        if self.ordered_index.borrow().is_some() {
+            tree.enable_ordered_index();
+        }
+        *tree.eviction.borrow_mut() = self.eviction.borrow().clone();
+        tree
+    }
+
+    // Copy the tree's structure and keys into a new tree whose values are all `U::default()`.
+    // Sibling order and `max_children` carry over; values and secondary tags do not
+    pub fn clone_shape<U: Default + Clone>(&self) -> MultiIndexedTree<K, U> {
+        let mut tree = MultiIndexedTree::new(self.root().key().clone(), U::default());
+        tree.max_children = self.max_children;
+        let nodes = ParentIterator {
+            stack: vec![(None, self.root())],
+        };
+
+        // Pre-order guarantees each parent is copied before its children
+        for (parent, node) in nodes {
+            if let Some(parent) = parent {
+                let _ = tree.insert(&parent.key(), node.key().clone(), U::default());
+            }
         }
+        tree
+    }
+
+    // Deep-clone every subtree into its own standalone tree, one per node, in depth-first
+    // pre-order (so the first entry is a copy of the whole tree). This copies each node once
+    // per ancestor, i.e. O(n * depth) work and memory, so reserve it for small trees
+    pub fn subtrees(&self) -> Vec<MultiIndexedTree<K, T>> {
+        self.iter_depth_first()
+            .map(|node| Self::clone_subtree(&node))
+            .collect()
+    }
+
+    // Check whether the given node can take one more child under the `max_children` limit
+    fn has_room_for_child(&self, parent: &Node<K, T>) -> bool {
+        self.max_children
+            .is_none_or(|max| parent.children.borrow().len() < max)
     }
 
     // Insert a new node under the given parent key
@@ -167,9 +574,18 @@ where
 
         match parent {
             Some(parent_node) => {
+                if !self.has_room_for_child(&parent_node) {
+                    return Err("Parent has reached the maximum number of children".to_string());
+                }
+                if !self.make_room(&parent_node) {
+                    return Err("Tree is full and has no evictable leaf".to_string());
+                }
+
                 let new_node = Node::new(key.clone(), value);
                 parent_node.adopt(&new_node, &mut self.index.borrow_mut()); // Pass the index map
 
+                self.index_ordered(&new_node);
+                self.touch(&key);
                 self.index.borrow_mut().insert(key, new_node);
                 Ok(())
             }
@@ -177,167 +593,2839 @@ where
         }
     }
 
-    // Remove a node by key
+    // Remove a node and its descendants by key. The remaining siblings keep their order
     pub fn remove(&self, key: &K) -> Result<(), String> {
         let node = self.index.borrow().get(key).cloned();
 
         match node {
             Some(node) => {
-                node.detach(&mut self.index.borrow_mut());
+                self.extract_subtree(&node);
                 Ok(())
             }
             None => Err("Key not found".to_string()),
         }
     }
 
-    // Find a node by its key
-    pub fn find(&self, key: &K) -> Option<Rc<Node<K, T>>> {
-        self.index.borrow().get(key).cloned()
-    }
+    // Shrink the tree to at most `max_nodes` nodes by removing leaves, deepest first and right
+    // to left within a level, so the structure near the root survives longest. This is reverse
+    // breadth-first order, in which every node is already a leaf by the time it is removed. The
+    // root is never removed, so a limit of 0 still leaves it. Returns the removed keys in
+    // removal order
+    pub fn limit_size(&self, max_nodes: usize) -> Vec<K> {
+        let nodes: Vec<_> = self.iter_breadth_first().collect();
+        let excess = nodes.len().saturating_sub(max(max_nodes, 1));
 
-    // Add a key to the secondary index under a specific tag
-    pub fn add_to_secondary_index(&self, tag: String, key: K) {
-        self.secondary_index
-            .borrow_mut()
-            .entry(tag)
-            .or_insert_with(Vec::new)
-            .push(key);
+        let mut removed = Vec::with_capacity(excess);
+        for node in nodes.iter().rev().take(excess) {
+            if let Some(parent) = node.parent() {
+                parent.remove_child(node);
+            }
+            self.unindex_ordered(node);
+            let key = node.key().clone();
+            self.index.borrow_mut().remove(&key);
+            removed.push(key);
+        }
+        removed
     }
 
-    // Find all nodes associated with a specific tag in the secondary index
-    pub fn find_by_secondary_index(&self, tag: &str) -> Option<Vec<Rc<Node<K, T>>>> {
-        self.secondary_index
-            .borrow()
-            .get(tag)
-            .map(|keys| keys.iter().filter_map(|k| self.find(k)).collect())
-    }
+    // Remove every non-root node matching `predicate`, re-parenting its children under its
+    // parent in its place (see `flatten_into_parent`). The predicate is evaluated once per node
+    // against the tree as it was before any collapsing. Nodes whose collapse would exceed
+    // `max_children` on the parent are left in place
+    pub fn collapse_if<F: Fn(&Rc<Node<K, T>>) -> bool>(&self, predicate: F) {
+        let matching: Vec<K> = self
+            .iter_depth_first()
+            .filter(|node| !node.is_root() && predicate(node))
+            .map(|node| node.key().clone())
+            .collect();
 
-    // Create a depth-first iterator for the tree
-    pub fn iter_depth_first(&self) -> DepthFirstIterator<K, T> {
-        DepthFirstIterator {
-            stack: vec![self.root.clone()],
+        for key in matching.iter() {
+            let _ = self.flatten_into_parent(key);
         }
     }
 
-    // Create a breadth-first iterator for the tree
-    pub fn iter_breadth_first(&self) -> BreadthFirstIterator<K, T> {
-        BreadthFirstIterator {
-            queue: VecDeque::from(vec![self.root.clone()]),
+    // Deduplicate the children of the given node. Children are grouped left to right: each
+    // ungrouped child starts a group and pulls in every later ungrouped sibling for which
+    // `should_merge(first, sibling)` holds, judged on the original values. Each group then
+    // collapses into its first node: `combine` folds every other member's value into it and
+    // their children are appended to its own, in order. Fails without changing anything if
+    // the node is missing or a merged node would exceed `max_children`
+    pub fn merge_siblings_by<F>(
+        &self,
+        parent_key: &K,
+        should_merge: F,
+        combine: impl Fn(&mut T, &T),
+    ) -> Result<(), TreeError<K>>
+    where
+        F: Fn(&Rc<Node<K, T>>, &Rc<Node<K, T>>) -> bool,
+    {
+        let parent = self
+            .lookup(parent_key)
+            .ok_or_else(|| TreeError::KeyNotFound(parent_key.clone()))?;
+
+        // Plan every merge before touching the tree
+        let children = parent.children.borrow().clone();
+        let mut grouped = vec![false; children.len()];
+        let mut groups = Vec::new();
+        for (first, survivor) in children.iter().enumerate() {
+            if grouped[first] {
+                continue;
+            }
+            let mut merged = Vec::new();
+            for (later, sibling) in children.iter().enumerate().skip(first + 1) {
+                if !grouped[later] && should_merge(survivor, sibling) {
+                    grouped[later] = true;
+                    merged.push(sibling.clone());
+                }
+            }
+            if merged.is_empty() {
+                continue;
+            }
+
+            let total = survivor.children.borrow().len()
+                + merged
+                    .iter()
+                    .map(|node| node.children.borrow().len())
+                    .sum::<usize>();
+            if self.max_children.is_some_and(|max| total > max) {
+                return Err(TreeError::ChildLimitReached(survivor.key().clone()));
+            }
+            groups.push((survivor.clone(), merged));
         }
+
+        for (survivor, merged) in groups {
+            for node in merged {
+                combine(&mut survivor.value.borrow_mut(), &node.value());
+
+                let start = survivor.children.borrow().len();
+                let adopted: Vec<_> = node.children.borrow_mut().drain(..).collect();
+                for child in adopted.iter() {
+                    *child.parent.borrow_mut() = Some(Rc::downgrade(&survivor));
+                }
+                survivor.children.borrow_mut().extend(adopted);
+                survivor.reindex_children_from(start);
+
+                parent.remove_child(&node);
+                self.unindex_ordered(&node);
+                self.index.borrow_mut().remove(&*node.key());
+            }
+        }
+        Ok(())
     }
 
-    // Create a shortest path iterator
-    pub fn iter_shortest_path(&self) -> ShortestPathIterator<K, T> {
-        let mut queue = VecDeque::new();
-        queue.push_back((0, self.root.clone())); // Start with the root at depth 0
-        ShortestPathIterator { queue }
+    // Remove the subtree rooted at the given key and return it as a standalone tree. The
+    // remaining siblings keep their order, so `attach_subtree` at the node's old position
+    // (see `index_path`) puts it back where it came from
+    pub fn remove_subtree(&self, key: &K) -> Result<MultiIndexedTree<K, T>, TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        if node.is_root() {
+            return Err(TreeError::RootNode(key.clone()));
+        }
+
+        Ok(self.extract_subtree(&node))
     }
 
-    // Implement Dijkstra's algorithm to find all shortest paths from start to end
-    pub fn dijkstra_shortest_paths(
+    // Like `remove_subtree`, but starting from a node handle the caller already holds (e.g. a
+    // `find` result). Fails if the handle is not the node indexed under its key in this tree,
+    // or if it is the root
+    pub fn remove_node_subtree(
         &self,
-        start_key: &K,
-        end_key: &K,
-    ) -> Option<HashMap<usize, Vec<K>>> {
-        let mut distances: HashMap<K, usize> = HashMap::new();
-        let mut predecessors: HashMap<K, Vec<K>> = HashMap::new();
-        let mut queue = VecDeque::new();
-
-        // Initialize distances
-        for key in self.index.borrow().keys() {
-            distances.insert(key.clone(), usize::MAX);
+        node: &Rc<Node<K, T>>,
+    ) -> Result<MultiIndexedTree<K, T>, TreeError<K>> {
+        let key = node.key().clone();
+        match self.lookup(&key) {
+            Some(indexed) if Rc::ptr_eq(&indexed, node) => {}
+            _ => return Err(TreeError::KeyNotFound(key)),
+        }
+        if node.is_root() {
+            return Err(TreeError::RootNode(key));
         }
 
-        // Set the start node's distance to 0
-        distances.insert(start_key.clone(), 0);
-        queue.push_back(start_key.clone());
-
-        // Perform the BFS-style traversal
-        while let Some(current_key) = queue.pop_front() {
-            let current_distance = *distances.get(&current_key).unwrap();
-            if let Some(node) = self.index.borrow().get(&current_key) {
-                for child in node.children.borrow().iter() {
-                    let child_key = child.key.clone();
-                    let new_distance = current_distance + 1; // Assuming unweighted edges (weight = 1)
+        Ok(self.extract_subtree(node))
+    }
 
-                    if new_distance < *distances.get(&child_key).unwrap() {
-                        distances.insert(child_key.clone(), new_distance);
-                        predecessors.insert(child_key.clone(), vec![current_key.clone()]);
-                        queue.push_back(child_key.clone());
-                    } else if new_distance == *distances.get(&child_key).unwrap() {
-                        // Add an additional predecessor for ties in shortest paths
-                        predecessors
-                            .entry(child_key.clone())
-                            .or_default()
-                            .push(current_key.clone());
-                    }
-                }
-            }
+    // Cut the edge above the given node, returning `(detached, remaining)`: the subtree rooted
+    // at `key`, and the rest of the tree. This tree itself becomes the remaining part; since it
+    // cannot be moved out from behind `&self`, `remaining` is an independent deep copy of it
+    // (O(n)). Sibling order around the cut is preserved, and the secondary index is split too:
+    // each detached node's tags move to `detached`, keeping their order within each tag
+    pub fn split_at(&self, key: &K) -> Result<SplitTrees<K, T>, TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        if node.is_root() {
+            return Err(TreeError::RootNode(key.clone()));
         }
 
-        // Trace back from the end node to gather paths
-        fn trace_paths<K: Clone + Eq + Hash>(
-            end_key: &K,
-            predecessors: &HashMap<K, Vec<K>>,
-            current_path: &mut Vec<K>,
-            all_paths: &mut HashMap<usize, Vec<K>>,
-        ) {
-            current_path.push(end_key.clone());
-            if let Some(preds) = predecessors.get(end_key) {
-                for pred in preds {
-                    trace_paths(pred, predecessors, current_path, all_paths);
+        let detached = self.extract_subtree(&node);
+        {
+            let moved = detached.index.borrow();
+            let mut secondary_index = self.secondary_index.borrow_mut();
+            let mut detached_tags = detached.secondary_index.borrow_mut();
+            // A tag that only covered detached nodes leaves this tree entirely
+            secondary_index.retain(|tag, keys| {
+                let (taken, kept): (Vec<K>, Vec<K>) =
+                    keys.drain(..).partition(|key| moved.contains_key(key));
+                *keys = kept;
+                if taken.is_empty() {
+                    return true;
                 }
-            } else {
-                // Base case: no predecessor, add reversed path
-                let mut path = current_path.clone();
-                path.reverse();
-                all_paths.insert(path.len(), path);
-            }
-            current_path.pop();
+                detached_tags.insert(tag.clone(), taken);
+                !keys.is_empty()
+            });
         }
+        Ok((detached, self.deep_clone()))
+    }
 
-        if distances[end_key] == usize::MAX {
-            return None; // No path exists
+    // Detach every child of the given node, returning each as a standalone tree in the
+    // original child order. The node itself stays in place as a leaf
+    pub fn detach_children(&self, key: &K) -> Result<Vec<MultiIndexedTree<K, T>>, TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+
+        let children = node.children.borrow().clone();
+        Ok(children
+            .iter()
+            .map(|child| self.extract_subtree(child))
+            .collect())
+    }
+
+    // Keep only the first `n` children of the given node, detaching the rest and returning
+    // them as standalone trees in their original order. Nothing is removed if the node has
+    // `n` or fewer children
+    pub fn truncate_children(
+        &self,
+        key: &K,
+        n: usize,
+    ) -> Result<Vec<MultiIndexedTree<K, T>>, TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+
+        let excess: Vec<Rc<Node<K, T>>> = node.children.borrow().iter().skip(n).cloned().collect();
+        Ok(excess
+            .iter()
+            .map(|child| self.extract_subtree(child))
+            .collect())
+    }
+
+    // Swap the subtree at `key` for `replacement`, which takes over the same parent and sibling
+    // position. The replacement's secondary-index tags are merged into this tree. Fails without
+    // changing anything if `key` is missing or the root, if a replacement key is already used
+    // outside the subtree being replaced, or if the swap would exceed the tree's node capacity.
+    // Returns the removed subtree
+    pub fn replace_subtree(
+        &self,
+        key: &K,
+        replacement: MultiIndexedTree<K, T>,
+    ) -> Result<MultiIndexedTree<K, T>, TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        let parent = node
+            .parent()
+            .ok_or_else(|| TreeError::RootNode(key.clone()))?;
+
+        let replaced: HashSet<K> = DepthFirstIterator {
+            stack: vec![node.clone()],
         }
+        .map(|node| node.key().clone())
+        .collect();
+        self.check_graft(&replacement, &replaced)?;
 
-        let mut all_paths = HashMap::new();
-        let mut current_path = Vec::new();
-        trace_paths(end_key, &predecessors, &mut current_path, &mut all_paths);
+        let position = *node.index.borrow();
+        let removed = self.extract_subtree(&node);
+        self.graft_at(&parent, replacement, position);
+        Ok(removed)
+    }
 
-        Some(all_paths)
+    // Graft a standalone tree (e.g. one returned by `remove_subtree` or `split_at`) under the
+    // given parent, at `position` among its children or appended when `None`. The subtree's
+    // nodes and secondary-index tags are merged into this tree. Fails without changing anything
+    // if the parent is missing or full, if any subtree key is already in use, or if the tree
+    // would exceed its node capacity
+    pub fn attach_subtree(
+        &self,
+        parent_key: &K,
+        subtree: MultiIndexedTree<K, T>,
+        position: Option<usize>,
+    ) -> Result<(), TreeError<K>> {
+        let parent = self
+            .lookup(parent_key)
+            .ok_or_else(|| TreeError::KeyNotFound(parent_key.clone()))?;
+        if !self.has_room_for_child(&parent) {
+            return Err(TreeError::ChildLimitReached(parent_key.clone()));
+        }
+        self.check_graft(&subtree, &HashSet::new())?;
+
+        self.graft_at(&parent, subtree, position.unwrap_or(usize::MAX));
+        Ok(())
     }
-}
 
-// Depth-First Iterator
-pub struct DepthFirstIterator<K, T>
-where
-    K: Eq + Hash,
-{
-    stack: Vec<Rc<Node<K, T>>>,
-}
+    // Unlink a node from its parent without disturbing sibling order, drop it and its
+    // descendants from this tree's indexes, and wrap it up as a standalone tree
+    fn extract_subtree(&self, node: &Rc<Node<K, T>>) -> MultiIndexedTree<K, T> {
+        if let Some(parent) = node.parent() {
+            parent.remove_child(node);
+        }
+        self.unindex_ordered(node);
 
-impl<K, T> Iterator for DepthFirstIterator<K, T>
-where
-    K: Eq + Hash,
-{
-    type Item = Rc<Node<K, T>>;
+        let subtree = MultiIndexedTree::from_root(node.clone());
+        let mut index = self.index.borrow_mut();
+        for key in subtree.index.borrow().keys() {
+            index.remove(key);
+        }
+        subtree
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(node) = self.stack.pop() {
-            for child in node.children.borrow().iter().rev() {
-                self.stack.push(child.clone());
+    // Check that `subtree` can be grafted into this tree: its keys must not already be in use
+    // (except by nodes in `replaced`, which are about to be removed), none of its nodes may
+    // exceed `max_children`, and the result must fit a capacity-bounded tree. Grafts never
+    // evict to make room; unlike a single insert, that could empty out half the tree
+    fn check_graft(
+        &self,
+        subtree: &MultiIndexedTree<K, T>,
+        replaced: &HashSet<K>,
+    ) -> Result<(), TreeError<K>> {
+        let index = self.index.borrow();
+        if let Some(eviction) = self.eviction.borrow().as_ref() {
+            if index.len() - replaced.len() + subtree.index.borrow().len() > eviction.max_nodes {
+                return Err(TreeError::CapacityExceeded(subtree.root().key().clone()));
             }
-            Some(node)
-        } else {
-            None
         }
+        for (key, node) in subtree.index.borrow().iter() {
+            if index.contains_key(key) && !replaced.contains(key) {
+                return Err(TreeError::DuplicateKey(key.clone()));
+            }
+            if self
+                .max_children
+                .is_some_and(|max| node.children.borrow().len() > max)
+            {
+                return Err(TreeError::ChildLimitReached(key.clone()));
+            }
+        }
+        Ok(())
     }
-}
 
-// Breadth-First Iterator
-pub struct BreadthFirstIterator<K, T>
-where
-    K: Eq + Hash,
+    // Attach `subtree`'s root as the `position`-th child of `parent` and take over its primary
+    // and secondary index entries
+    fn graft_at(&self, parent: &Rc<Node<K, T>>, subtree: MultiIndexedTree<K, T>, position: usize) {
+        parent.insert_child(&subtree.root(), position);
+
+        for (key, node) in subtree.index.borrow().iter() {
+            self.touch(key);
+            self.index_ordered(node);
+            self.index.borrow_mut().insert(key.clone(), node.clone());
+        }
+
+        let mut secondary_index = self.secondary_index.borrow_mut();
+        for (tag, keys) in subtree.secondary_index.borrow().iter() {
+            secondary_index
+                .entry(tag.clone())
+                .or_default()
+                .extend(keys.iter().cloned());
+        }
+    }
+
+    // Remove a node but keep its children, re-parenting them under its former parent at the
+    // position the node used to occupy
+    pub fn flatten_into_parent(&self, key: &K) -> Result<(), TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        let parent = node
+            .parent()
+            .ok_or_else(|| TreeError::RootNode(key.clone()))?;
+
+        if let Some(max) = self.max_children {
+            if parent.children.borrow().len() - 1 + node.children.borrow().len() > max {
+                return Err(TreeError::ChildLimitReached(parent.key().clone()));
+            }
+        }
+
+        let position = *node.index.borrow();
+        let children: Vec<_> = node.children.borrow_mut().drain(..).collect();
+        for child in children.iter() {
+            *child.parent.borrow_mut() = Some(Rc::downgrade(&parent));
+        }
+
+        *node.parent.borrow_mut() = None;
+        parent
+            .children
+            .borrow_mut()
+            .splice(position..=position, children);
+        parent.reindex_children_from(position);
+        self.unindex_ordered(&node);
+        self.index.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    // Replace the value stored under the given key
+    pub fn update(&self, key: &K, value: T) -> Result<(), TreeError<K>> {
+        let node = self
+            .find(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        node.set_value(value);
+        Ok(())
+    }
+
+    // Move a node (with its subtree) so it becomes the last child of a new parent
+    pub fn move_node(&self, key: &K, new_parent_key: &K) -> Result<(), TreeError<K>> {
+        self.move_node_at(key, new_parent_key, usize::MAX)
+    }
+
+    // Move a node (with its subtree) so it becomes the `position`-th child of a new parent.
+    // Positions past the end append the node; sibling order is otherwise preserved on both sides
+    pub fn move_node_at(
+        &self,
+        key: &K,
+        new_parent_key: &K,
+        position: usize,
+    ) -> Result<(), TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+        let new_parent = self
+            .lookup(new_parent_key)
+            .ok_or_else(|| TreeError::KeyNotFound(new_parent_key.clone()))?;
+        let old_parent = node
+            .parent()
+            .ok_or_else(|| TreeError::RootNode(key.clone()))?;
+
+        // The new parent must not be the node itself or one of its descendants
+        let mut ancestor = Some(new_parent.clone());
+        while let Some(current) = ancestor {
+            if Rc::ptr_eq(&current, &node) {
+                return Err(TreeError::CycleDetected(key.clone()));
+            }
+            ancestor = current.parent();
+        }
+
+        if !Rc::ptr_eq(&old_parent, &new_parent) && !self.has_room_for_child(&new_parent) {
+            return Err(TreeError::ChildLimitReached(new_parent_key.clone()));
+        }
+
+        old_parent.remove_child(&node);
+        new_parent.insert_child(&node, position);
+        Ok(())
+    }
+
+    // Rebuild the tree so that its height is as small as possible for its arity. The root stays
+    // in place and every other node is re-parented into a complete tree filled level by level in
+    // sorted key order, so siblings end up ordered by key. The arity is `max_children` when set,
+    // otherwise 2 (a plain star would be minimal but rarely useful). Values, keys and the set
+    // of nodes are preserved; the original parent/child relationships are not
+    pub fn rebalance(&self) {
+        let arity = self.max_children.unwrap_or(2).max(1);
+        let root = self.root();
+
+        let mut nodes: Vec<_> = self
+            .index
+            .borrow()
+            .values()
+            .filter(|node| !Rc::ptr_eq(node, &root))
+            .cloned()
+            .collect();
+        nodes.sort_by(|a, b| a.key().cmp(&b.key()));
+
+        root.children.borrow_mut().clear();
+        for node in nodes.iter() {
+            node.children.borrow_mut().clear();
+        }
+
+        // With the root at position 0, the node at position p has its parent at (p - 1) / arity
+        for (i, node) in nodes.iter().enumerate() {
+            let parent = match i / arity {
+                0 => &root,
+                parent_position => &nodes[parent_position - 1],
+            };
+            parent.insert_child(node, usize::MAX);
+        }
+    }
+
+    // Re-orient the tree so the given node becomes the root. Every parent/child link on the
+    // path from the old root down to the new one is reversed: each former parent is appended
+    // as the last child of the node below it. Nodes off that path keep their parents
+    pub fn reroot(&self, new_root_key: &K) -> Result<(), TreeError<K>> {
+        let new_root = self
+            .lookup(new_root_key)
+            .ok_or_else(|| TreeError::KeyNotFound(new_root_key.clone()))?;
+
+        // Path from the new root up to the old one
+        let mut path = vec![new_root.clone()];
+        while let Some(parent) = path[path.len() - 1].parent() {
+            path.push(parent);
+        }
+
+        if path.len() == 1 {
+            return Ok(()); // Already the root
+        }
+
+        // Every node on the path swaps one child for another, except the new root which gains one
+        if !self.has_room_for_child(&new_root) {
+            return Err(TreeError::ChildLimitReached(new_root_key.clone()));
+        }
+
+        for pair in path.windows(2) {
+            pair[1].remove_child(&pair[0]);
+        }
+        for pair in path.windows(2) {
+            pair[0].insert_child(&pair[1], usize::MAX);
+        }
+
+        *self.root.borrow_mut() = new_root;
+        Ok(())
+    }
+
+    // Change a node's key in place, keeping the node (and any handles to it) intact. The
+    // primary index, the ordered index, every secondary-index bucket and the LRU access
+    // history (see `with_capacity_eviction`) are updated to the new key. Fails if `old` is
+    // missing or `new` is already used by another node
+    pub fn rename_key(&self, old: &K, new: K) -> Result<(), TreeError<K>> {
+        let node = self
+            .lookup(old)
+            .ok_or_else(|| TreeError::KeyNotFound(old.clone()))?;
+        if *old == new {
+            return Ok(());
+        }
+        if self.lookup(&new).is_some() {
+            return Err(TreeError::DuplicateKey(new));
+        }
+
+        *node.key.borrow_mut() = new.clone();
+
+        let mut index = self.index.borrow_mut();
+        index.remove(old);
+        index.insert(new.clone(), node.clone());
+
+        if let Some(ordered) = self.ordered_index.borrow_mut().as_mut() {
+            ordered.remove(old);
+            ordered.insert(new.clone(), node);
+        }
+
+        for keys in self.secondary_index.borrow_mut().values_mut() {
+            for key in keys.iter_mut().filter(|key| **key == *old) {
+                *key = new.clone();
+            }
+        }
+
+        // Carry the node's access history over, so the rename does not make it an LRU victim
+        if let Some(eviction) = self.eviction.borrow_mut().as_mut() {
+            if let Some(last_access) = eviction.last_access.remove(old) {
+                eviction.last_access.insert(new, last_access);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Find a node by its key (an access, for trees created with `with_capacity_eviction`)
+    pub fn find(&self, key: &K) -> Option<Rc<Node<K, T>>> {
+        let node = self.lookup(key);
+        if node.is_some() {
+            self.touch(key);
+        }
+        node
+    }
+
+    // Find a node by its key without counting as an access. Internal queries use this, so that
+    // only the caller's own `find` and `update` calls reorder the LRU eviction queue
+    fn lookup(&self, key: &K) -> Option<Rc<Node<K, T>>> {
+        self.index.borrow().get(key).cloned()
+    }
+
+    // Find all nodes whose keys fall in `[start, end)`, sorted by key. Uses the ordered index
+    // when enabled (see `enable_ordered_index`), otherwise scans the primary index
+    pub fn range(&self, start: &K, end: &K) -> Vec<Rc<Node<K, T>>> {
+        if start >= end {
+            return Vec::new();
+        }
+
+        if let Some(ordered) = self.ordered_index.borrow().as_ref() {
+            return ordered
+                .range(start.clone()..end.clone())
+                .map(|(_, node)| node.clone())
+                .collect();
+        }
+
+        let mut nodes: Vec<_> = self
+            .index
+            .borrow()
+            .iter()
+            .filter(|(key, _)| *key >= start && *key < end)
+            .map(|(_, node)| node.clone())
+            .collect();
+        nodes.sort_by(|a, b| a.key().cmp(&b.key()));
+        nodes
+    }
+
+    // Find all nodes whose string key starts with `prefix`, sorted by key. Uses a range scan
+    // over the ordered index when enabled, otherwise scans the primary index
+    pub fn find_by_prefix(&self, prefix: &str) -> Vec<Rc<Node<K, T>>>
+    where
+        K: Borrow<str>,
+    {
+        let matches = |key: &K| Borrow::<str>::borrow(key).starts_with(prefix);
+
+        if let Some(ordered) = self.ordered_index.borrow().as_ref() {
+            return ordered
+                .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| matches(key))
+                .map(|(_, node)| node.clone())
+                .collect();
+        }
+
+        let mut nodes: Vec<_> = self
+            .index
+            .borrow()
+            .values()
+            .filter(|node| matches(&node.key()))
+            .cloned()
+            .collect();
+        nodes.sort_by(|a, b| a.key().cmp(&b.key()));
+        nodes
+    }
+
+    // Get the keys of a node's direct children, in order
+    pub fn child_keys(&self, key: &K) -> Option<Vec<K>> {
+        let node = self.lookup(key)?;
+        let keys = node
+            .children
+            .borrow()
+            .iter()
+            .map(|child| child.key().clone())
+            .collect();
+        Some(keys)
+    }
+
+    // Get the sibling immediately after the given node, if any
+    pub fn next_sibling(&self, key: &K) -> Option<Rc<Node<K, T>>> {
+        let node = self.lookup(key)?;
+        let position = *node.index.borrow();
+        let parent = node.parent()?;
+        let sibling = parent.children.borrow().get(position + 1).cloned();
+        sibling
+    }
+
+    // Get the sibling immediately before the given node, if any
+    pub fn prev_sibling(&self, key: &K) -> Option<Rc<Node<K, T>>> {
+        let node = self.lookup(key)?;
+        let position = node.index.borrow().checked_sub(1)?;
+        let parent = node.parent()?;
+        let sibling = parent.children.borrow().get(position).cloned();
+        sibling
+    }
+
+    // Get a node's degree when the tree is viewed as an undirected graph: its number of
+    // children plus one for the edge to its parent (the root has no parent edge)
+    pub fn degree(&self, key: &K) -> Option<usize> {
+        let node = self.lookup(key)?;
+        let children = node.children.borrow().len();
+        Some(children + usize::from(!node.is_root()))
+    }
+
+    // Check whether two distinct nodes share the same parent. The root has no siblings
+    pub fn are_siblings(&self, a: &K, b: &K) -> bool {
+        if a == b {
+            return false;
+        }
+
+        let parent_key = |key: &K| {
+            self.lookup(key)?
+                .parent()
+                .map(|parent| parent.key().clone())
+        };
+        match (parent_key(a), parent_key(b)) {
+            (Some(a_parent), Some(b_parent)) => a_parent == b_parent,
+            _ => false,
+        }
+    }
+
+    // Check whether `target` lies inside the subtree rooted at `root_key`, i.e. is that node or
+    // one of its descendants. False if either key is missing
+    pub fn subtree_contains(&self, root_key: &K, target: &K) -> bool {
+        let Some(root) = self.lookup(root_key) else {
+            return false;
+        };
+
+        let mut current = self.lookup(target);
+        while let Some(node) = current {
+            if Rc::ptr_eq(&node, &root) {
+                return true;
+            }
+            current = node.parent();
+        }
+        false
+    }
+
+    // Find all nodes at most `radius` edges away from the given node (excluding the node
+    // itself), treating the tree as undirected. Results are ordered by increasing distance;
+    // a missing key yields an empty Vec
+    pub fn neighbors_within(&self, key: &K, radius: usize) -> Vec<Rc<Node<K, T>>> {
+        let start = match self.lookup(key) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+
+        let mut visited = HashSet::from([start.key().clone()]);
+        let mut queue = VecDeque::from([(0, start)]);
+        let mut neighbors = Vec::new();
+
+        while let Some((distance, node)) = queue.pop_front() {
+            if distance == radius {
+                continue;
+            }
+
+            let mut adjacent = node.children.borrow().clone();
+            adjacent.extend(node.parent());
+            for next in adjacent {
+                if visited.insert(next.key().clone()) {
+                    neighbors.push(next.clone());
+                    queue.push_back((distance + 1, next));
+                }
+            }
+        }
+
+        neighbors
+    }
+
+    // Sum the edge weights along the unique path between two nodes (through their LCA).
+    // Edges do not store a weight, so `edge_weight` is called with each node on the path
+    // below the LCA and returns the weight of the edge joining that node to its parent
+    pub fn path_weight<W, F>(&self, a: &K, b: &K, edge_weight: F) -> Option<W>
+    where
+        W: Add<Output = W> + Default,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        let lca = self.lca(a, b)?;
+        let mut total = W::default();
+
+        for key in [a, b] {
+            let mut current = self.lookup(key)?;
+            while !Rc::ptr_eq(&current, &lca) {
+                total = total + edge_weight(&current);
+                current = current.parent()?;
+            }
+        }
+
+        Some(total)
+    }
+
+    // Sum the weight of every edge in the tree, i.e. the incoming edge of each non-root node
+    // as reported by `edge_weight` (see `path_weight`)
+    pub fn total_weight<W, F>(&self, edge_weight: F) -> W
+    where
+        W: Add<Output = W> + Default,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        self.iter_depth_first()
+            .filter(|node| !node.is_root())
+            .fold(W::default(), |total, node| total + edge_weight(&node))
+    }
+
+    // Find the cheapest downward path from the given node to any leaf in its subtree, using
+    // `edge_weight` for edge costs (see `path_weight`). Returns the total weight and the key
+    // path (starting at `key`); ties go to the earlier child. `None` if the key is missing
+    pub fn min_weight_path_to_leaf<W, F>(&self, key: &K, edge_weight: F) -> Option<(W, Vec<K>)>
+    where
+        W: Add<Output = W> + Default + Ord + Clone,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        self.extreme_path_to_leaf(key, &edge_weight, &|candidate, best| candidate < best)
+    }
+
+    // Like `min_weight_path_to_leaf`, but finds the most expensive downward path
+    pub fn max_weight_path_to_leaf<W, F>(&self, key: &K, edge_weight: F) -> Option<(W, Vec<K>)>
+    where
+        W: Add<Output = W> + Default + Ord + Clone,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        self.extreme_path_to_leaf(key, &edge_weight, &|candidate, best| candidate > best)
+    }
+
+    // Treat node values as weights and find the root-to-leaf path with the largest sum of
+    // values, root and leaf included. Returns the total and the key path from the root. `T`
+    // must be `Ord` to compare totals and `Add<Output = T>` to sum them (values are cloned, as
+    // everywhere in this impl). Negative values are fine, since the path always runs to a
+    // leaf; ties go to the leftmost path
+    pub fn heaviest_root_leaf_path(&self) -> (T, Vec<K>)
+    where
+        T: Ord + Add<Output = T>,
+    {
+        // Returns the heaviest (total, reversed path) for the subtree rooted at `node`
+        fn solve<K, T>(node: &Rc<Node<K, T>>) -> (T, Vec<K>)
+        where
+            K: Eq + Hash + Clone,
+            T: Ord + Add<Output = T> + Clone,
+        {
+            let mut best: Option<(T, Vec<K>)> = None;
+            let children = node.children.borrow().clone();
+            for child in children.iter() {
+                let candidate = solve(child);
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_total, _)| candidate.0 > *best_total)
+                {
+                    best = Some(candidate);
+                }
+            }
+
+            let value = node.value().clone();
+            let (total, mut path) = match best {
+                Some((total, path)) => (value + total, path),
+                None => (value, Vec::new()),
+            };
+            path.push(node.key().clone());
+            (total, path)
+        }
+
+        let (total, mut path) = solve(&self.root());
+        path.reverse();
+        (total, path)
+    }
+
+    // Shared tree DP for the min/max path-to-leaf queries; `better` decides whether a
+    // candidate total beats the best one seen so far
+    fn extreme_path_to_leaf<W, F>(
+        &self,
+        key: &K,
+        edge_weight: &F,
+        better: &dyn Fn(&W, &W) -> bool,
+    ) -> Option<(W, Vec<K>)>
+    where
+        W: Add<Output = W> + Default + Ord + Clone,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        // Returns the best (weight, reversed path) for the subtree rooted at `node`
+        fn solve<K, T, W, F>(
+            node: &Rc<Node<K, T>>,
+            edge_weight: &F,
+            better: &dyn Fn(&W, &W) -> bool,
+        ) -> (W, Vec<K>)
+        where
+            K: Eq + Hash + Clone,
+            W: Add<Output = W> + Default + Clone,
+            F: Fn(&Rc<Node<K, T>>) -> W,
+        {
+            let mut best: Option<(W, Vec<K>)> = None;
+            let children = node.children.borrow().clone();
+            for child in children.iter() {
+                let (weight, path) = solve(child, edge_weight, better);
+                let total = edge_weight(child) + weight;
+                if best
+                    .as_ref()
+                    .is_none_or(|(best_total, _)| better(&total, best_total))
+                {
+                    best = Some((total, path));
+                }
+            }
+
+            let (total, mut path) = best.unwrap_or_else(|| (W::default(), Vec::new()));
+            path.push(node.key().clone());
+            (total, path)
+        }
+
+        let node = self.lookup(key)?;
+        let (total, mut path) = solve(&node, edge_weight, better);
+        path.reverse();
+        Some((total, path))
+    }
+
+    // Run A* from `start` to `end`, treating every edge as traversable in both directions.
+    // The open set is a min-heap ordered by `g + h`, where `h` comes from `heuristic` (which
+    // must never overestimate for the result to be optimal) and edge costs come from
+    // `edge_weight` (see `path_weight`). Returns the total cost and the key path, or `None`
+    // when either key is missing
+    pub fn astar<W, H, F>(
+        &self,
+        start: &K,
+        end: &K,
+        heuristic: H,
+        edge_weight: F,
+    ) -> Option<(W, Vec<K>)>
+    where
+        W: Add<Output = W> + Default + Ord + Clone,
+        H: Fn(&K) -> W,
+        F: Fn(&Rc<Node<K, T>>) -> W,
+    {
+        self.lookup(start)?;
+        self.lookup(end)?;
+
+        let mut g_scores: HashMap<K, W> = HashMap::from([(start.clone(), W::default())]);
+        let mut came_from: HashMap<K, K> = HashMap::new();
+        let mut closed: HashSet<K> = HashSet::new();
+        let mut open = BinaryHeap::from([Reverse((heuristic(start), start.clone()))]);
+
+        while let Some(Reverse((_, key))) = open.pop() {
+            if key == *end {
+                let mut path = vec![key.clone()];
+                while let Some(previous) = came_from.get(&path[path.len() - 1]) {
+                    path.push(previous.clone());
+                }
+                path.reverse();
+                return Some((g_scores[&key].clone(), path));
+            }
+
+            if !closed.insert(key.clone()) {
+                continue; // Stale heap entry for a node that was already expanded
+            }
+
+            let node = self.lookup(&key)?;
+            let g_score = g_scores[&key].clone();
+
+            // Moving down costs the child's incoming edge; moving up costs this node's own
+            let children = node.children.borrow().clone();
+            let mut neighbors: Vec<_> = children
+                .into_iter()
+                .map(|child| {
+                    let cost = edge_weight(&child);
+                    (child, cost)
+                })
+                .collect();
+            if let Some(parent) = node.parent() {
+                neighbors.push((parent, edge_weight(&node)));
+            }
+
+            for (next, cost) in neighbors {
+                if closed.contains(&*next.key()) {
+                    continue;
+                }
+
+                let tentative = g_score.clone() + cost;
+                if g_scores
+                    .get(&*next.key())
+                    .is_none_or(|known| tentative < *known)
+                {
+                    g_scores.insert(next.key().clone(), tentative.clone());
+                    came_from.insert(next.key().clone(), key.clone());
+                    open.push(Reverse((
+                        tentative + heuristic(&*next.key()),
+                        next.key().clone(),
+                    )));
+                }
+            }
+        }
+
+        None
+    }
+
+    // Find a node by a path of keys joined by `sep`, as produced by `iter_paths`. The first
+    // segment must be the root's key and each following segment must name a child of the
+    // previous node. Returns `None` if any segment fails to parse or match
+    pub fn find_by_path(&self, path: &str, sep: &str) -> Option<Rc<Node<K, T>>>
+    where
+        K: FromStr,
+    {
+        let mut segments = path.split(sep).map(|segment| segment.parse::<K>().ok());
+
+        let mut current = self.root();
+        if segments.next()?? != *current.key() {
+            return None;
+        }
+
+        for segment in segments {
+            let key = segment?;
+            let next = current
+                .children
+                .borrow()
+                .iter()
+                .find(|child| *child.key() == key)
+                .cloned()?;
+            current = next;
+        }
+
+        Some(current)
+    }
+
+    // Get a node's positional address: the child index taken at each step down from the root
+    // (e.g. `[1, 0]` is the root's second child's first child). The root's path is empty.
+    // Positions are unaffected by key renames but shift when earlier siblings are added or removed
+    pub fn index_path(&self, key: &K) -> Option<Vec<usize>> {
+        let mut path: Vec<usize> = self
+            .ancestors_to_root(key)?
+            .iter()
+            .filter(|node| !node.is_root())
+            .map(|node| *node.index.borrow())
+            .collect();
+        path.reverse();
+        Some(path)
+    }
+
+    // Find a node by its positional address (see `index_path`), descending from the root one
+    // child index at a time. Returns `None` if any index is out of range
+    pub fn find_by_index_path(&self, path: &[usize]) -> Option<Rc<Node<K, T>>> {
+        let mut current = self.root();
+        for &position in path {
+            let next = current.children.borrow().get(position).cloned()?;
+            current = next;
+        }
+        Some(current)
+    }
+
+    // Add a key to the secondary index under a specific tag. Every tag bucket keeps its keys
+    // in insertion order, and all secondary-index maintenance (removal, compaction, renaming)
+    // preserves that order, so `find_by_secondary_index` results are stable
+    pub fn add_to_secondary_index(&self, tag: String, key: K) {
+        self.secondary_index
+            .borrow_mut()
+            .entry(tag)
+            .or_default()
+            .push(key);
+    }
+
+    // Remove a key from a tag's bucket without reordering the remaining keys. Returns whether
+    // the key was tagged. The bucket is kept even if it becomes empty (see
+    // `compact_secondary_index`)
+    pub fn remove_from_secondary_index(&self, tag: &str, key: &K) -> bool {
+        let mut secondary_index = self.secondary_index.borrow_mut();
+        let Some(keys) = secondary_index.get_mut(tag) else {
+            return false;
+        };
+
+        match keys.iter().position(|tagged| tagged == key) {
+            Some(position) => {
+                keys.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Add several keys to a tag at once, creating the tag if needed. Keys already in the
+    // bucket (or repeated in `keys`) are only stored once
+    pub fn add_many_to_secondary_index(&self, tag: &str, keys: impl IntoIterator<Item = K>) {
+        let mut secondary_index = self.secondary_index.borrow_mut();
+        let bucket = secondary_index.entry(tag.to_string()).or_default();
+
+        let mut seen: HashSet<K> = bucket.iter().cloned().collect();
+        for key in keys {
+            if seen.insert(key.clone()) {
+                bucket.push(key);
+            }
+        }
+    }
+
+    // Tag nodes from their content: `classify` is called on every node (depth-first) and the
+    // node's key is added to whichever tag it returns. Keys already under that tag are skipped
+    pub fn tag_by<F: Fn(&Rc<Node<K, T>>) -> Option<String>>(&self, classify: F) {
+        let mut tagged: HashMap<String, Vec<K>> = HashMap::new();
+        for node in self.iter_depth_first() {
+            if let Some(tag) = classify(&node) {
+                tagged.entry(tag).or_default().push(node.key().clone());
+            }
+        }
+
+        for (tag, keys) in tagged {
+            self.add_many_to_secondary_index(&tag, keys);
+        }
+    }
+
+    // Find all nodes associated with a specific tag in the secondary index
+    pub fn find_by_secondary_index(&self, tag: &str) -> Option<Vec<Rc<Node<K, T>>>> {
+        self.secondary_index
+            .borrow()
+            .get(tag)
+            .map(|keys| keys.iter().filter_map(|k| self.lookup(k)).collect())
+    }
+
+    // Collect the node handles from the given node up to the root: the node itself comes
+    // first and the root last
+    pub fn ancestors_to_root(&self, key: &K) -> Option<Vec<Rc<Node<K, T>>>> {
+        let mut ancestors = vec![self.lookup(key)?];
+        while let Some(parent) = ancestors[ancestors.len() - 1].parent() {
+            ancestors.push(parent);
+        }
+        Some(ancestors)
+    }
+
+    // Walk up from the given node and return its closest proper ancestor satisfying
+    // `predicate`, e.g. to inherit the nearest setting. The node itself is not considered
+    pub fn nearest_ancestor<F: Fn(&Rc<Node<K, T>>) -> bool>(
+        &self,
+        key: &K,
+        predicate: F,
+    ) -> Option<Rc<Node<K, T>>> {
+        let mut current = self.lookup(key)?.parent();
+        while let Some(node) = current {
+            if predicate(&node) {
+                return Some(node);
+            }
+            current = node.parent();
+        }
+        None
+    }
+
+    // Breadth-first search for the first node satisfying `predicate`, i.e. the matching node
+    // closest to the root (ties at equal depth go to the leftmost)
+    pub fn find_shallowest<F: Fn(&Rc<Node<K, T>>) -> bool>(
+        &self,
+        predicate: F,
+    ) -> Option<Rc<Node<K, T>>> {
+        self.iter_breadth_first().find(|node| predicate(node))
+    }
+
+    // Find the lowest common ancestor of two nodes
+    pub fn lca(&self, a: &K, b: &K) -> Option<Rc<Node<K, T>>> {
+        let mut a = self.lookup(a)?;
+        let mut b = self.lookup(b)?;
+        let mut a_depth = a.depth();
+        let mut b_depth = b.depth();
+
+        // Lift the deeper node until both sit at the same depth
+        while a_depth > b_depth {
+            a = a.parent()?;
+            a_depth -= 1;
+        }
+        while b_depth > a_depth {
+            b = b.parent()?;
+            b_depth -= 1;
+        }
+
+        // Walk both up in lockstep until they meet
+        while !Rc::ptr_eq(&a, &b) {
+            a = a.parent()?;
+            b = b.parent()?;
+        }
+
+        Some(a)
+    }
+
+    // Find the deepest node that is an ancestor of (or equal to) every given key by folding
+    // pairwise LCA across the slice. Returns `None` for an empty slice or any missing key
+    pub fn lca_many(&self, keys: &[K]) -> Option<Rc<Node<K, T>>> {
+        let (first, rest) = keys.split_first()?;
+        rest.iter()
+            .try_fold(self.lookup(first)?, |lca, key| self.lca(&*lca.key(), key))
+    }
+
+    // Find the root of the smallest subtree containing every live node under `tag`, i.e. their
+    // LCA. Keys whose nodes have since been removed are ignored; returns `None` if the tag is
+    // unknown or has no live nodes
+    pub fn tag_subtree_root(&self, tag: &str) -> Option<Rc<Node<K, T>>> {
+        let keys: Vec<K> = self
+            .find_by_secondary_index(tag)?
+            .iter()
+            .map(|node| node.key().clone())
+            .collect();
+        self.lca_many(&keys)
+    }
+
+    // Build a new tree holding the given keys plus every ancestor needed to connect them, up
+    // to their lowest common ancestor which becomes the new root. Sibling order is preserved.
+    // Values (including those of connecting ancestors) are cloned from this tree. Missing keys
+    // are ignored; if none of the keys exist the result is a copy of the root alone
+    pub fn induced_subtree(&self, keys: &[K]) -> MultiIndexedTree<K, T> {
+        let present: Vec<K> = keys
+            .iter()
+            .filter(|key| self.lookup(key).is_some())
+            .cloned()
+            .collect();
+        let top = self.lca_many(&present).unwrap_or_else(|| self.root());
+
+        // Mark every node on the way from each key up to the new root
+        let mut kept = HashSet::from([top.key().clone()]);
+        for key in present.iter() {
+            let mut current = self.lookup(key);
+            while let Some(node) = current {
+                if !kept.insert(node.key().clone()) {
+                    break; // The rest of this chain is already marked
+                }
+                current = node.parent();
+            }
+        }
+
+        let subtree = MultiIndexedTree::new(top.key().clone(), top.value().clone());
+        let mut stack = vec![top];
+        while let Some(node) = stack.pop() {
+            for child in node.children.borrow().iter().rev() {
+                if kept.contains(&*child.key()) {
+                    stack.push(child.clone());
+                }
+            }
+            for child in node.children.borrow().iter() {
+                if kept.contains(&*child.key()) {
+                    let _ =
+                        subtree.insert(&*node.key(), child.key().clone(), child.value().clone());
+                }
+            }
+        }
+
+        subtree
+    }
+
+    // Collect the key path from the root to every leaf, with leaves in depth-first order
+    pub fn root_to_leaf_paths(&self) -> Vec<Vec<K>> {
+        let mut paths = Vec::new();
+        let mut stack = vec![(vec![self.root().key().clone()], self.root())];
+
+        while let Some((path, node)) = stack.pop() {
+            if node.is_leaf() {
+                paths.push(path);
+                continue;
+            }
+            for child in node.children.borrow().iter().rev() {
+                let mut child_path = path.clone();
+                child_path.push(child.key().clone());
+                stack.push((child_path, child.clone()));
+            }
+        }
+
+        paths
+    }
+
+    // Pair each root-to-leaf key path with its leaf's value, with leaves in depth-first order.
+    // Handy for flattening a hierarchy into labelled rows
+    pub fn leaf_paths(&self) -> Vec<(Vec<K>, T)> {
+        let index = self.index.borrow();
+        self.root_to_leaf_paths()
+            .into_iter()
+            .map(|path| {
+                let value = index[&path[path.len() - 1]].value().clone();
+                (path, value)
+            })
+            .collect()
+    }
+
+    // Get the key path shared by two nodes: from the root down to their LCA, inclusive
+    pub fn common_prefix_path(&self, a: &K, b: &K) -> Option<Vec<K>> {
+        let lca = self.lca(a, b)?;
+        let mut path: Vec<K> = self
+            .ancestors_to_root(&lca.key())?
+            .iter()
+            .map(|node| node.key().clone())
+            .collect();
+        path.reverse();
+        Some(path)
+    }
+
+    // Find the unique path between two nodes, going up from `a` to their LCA and then down to `b`
+    pub fn path_between(&self, a: &K, b: &K) -> Option<Vec<K>> {
+        let lca = self.lca(a, b)?;
+
+        // Upward half: a -> lca (inclusive)
+        let mut path = Vec::new();
+        let mut current = self.lookup(a)?;
+        while !Rc::ptr_eq(&current, &lca) {
+            path.push(current.key().clone());
+            current = current.parent()?;
+        }
+        path.push(lca.key().clone());
+
+        // Downward half: collected from b upwards, then reversed
+        let mut downward = Vec::new();
+        let mut current = self.lookup(b)?;
+        while !Rc::ptr_eq(&current, &lca) {
+            downward.push(current.key().clone());
+            current = current.parent()?;
+        }
+        path.extend(downward.into_iter().rev());
+
+        Some(path)
+    }
+
+    // Count the edges on the path between two nodes without materializing it
+    pub fn distance(&self, a: &K, b: &K) -> Option<usize> {
+        let lca_depth = self.lca(a, b)?.depth();
+        let a_depth = self.lookup(a)?.depth();
+        let b_depth = self.lookup(b)?.depth();
+        Some(a_depth + b_depth - 2 * lca_depth)
+    }
+
+    // Call `f` with each node's key and a mutable borrow of its value.
+    // Nodes are visited in depth-first pre-order, the same order as `iter_depth_first`
+    pub fn for_each_value_mut<F: FnMut(&K, &mut T)>(&self, mut f: F) {
+        for node in self.iter_depth_first() {
+            f(&*node.key(), &mut node.value.borrow_mut());
+        }
+    }
+
+    // Call `f` with a mutable borrow of the value of every node in the subtree rooted at the
+    // given key, in depth-first pre-order. The subtree-scoped counterpart to `for_each_value_mut`
+    pub fn transform_subtree<F: FnMut(&mut T)>(
+        &self,
+        key: &K,
+        mut f: F,
+    ) -> Result<(), TreeError<K>> {
+        let node = self
+            .lookup(key)
+            .ok_or_else(|| TreeError::KeyNotFound(key.clone()))?;
+
+        let nodes = DepthFirstIterator { stack: vec![node] };
+        for node in nodes {
+            f(&mut node.value.borrow_mut());
+        }
+        Ok(())
+    }
+
+    // Compare this tree against `other`, reporting added, removed, and changed keys.
+    // A key counts as changed when its value differs or when its parent differs; parents
+    // are compared by key, so a node moved under a different parent is reported even if
+    // its value is unchanged. Each list is sorted by key.
+    pub fn diff(&self, other: &MultiIndexedTree<K, T>) -> TreeDiff<K>
+    where
+        T: PartialEq,
+    {
+        let ours = self.index.borrow();
+        let theirs = other.index.borrow();
+        let mut diff = TreeDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+
+        for (key, node) in ours.iter() {
+            match theirs.get(key) {
+                Some(other_node) => {
+                    let parent_key = node.parent().map(|p| p.key().clone());
+                    let other_parent_key = other_node.parent().map(|p| p.key().clone());
+                    if *node.value() != *other_node.value() || parent_key != other_parent_key {
+                        diff.changed.push(key.clone());
+                    }
+                }
+                None => diff.removed.push(key.clone()),
+            }
+        }
+
+        for key in theirs.keys() {
+            if !ours.contains_key(key) {
+                diff.added.push(key.clone());
+            }
+        }
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    // Merge `other` into this tree. Keys present in both keep this tree's position and take
+    // the value returned by `on_conflict(ours, theirs)`. Keys only present in `other` are
+    // inserted under the same parent key they have in `other`; if `other`'s root is new, it
+    // is placed under this tree's root. Branches that would exceed `max_children` are skipped.
+    pub fn merge(&self, other: &MultiIndexedTree<K, T>, on_conflict: impl Fn(&T, &T) -> T) {
+        // Breadth-first order guarantees a parent is merged before its children
+        for node in other.iter_breadth_first() {
+            match self.lookup(&*node.key()) {
+                Some(existing) => {
+                    let merged = on_conflict(&existing.value(), &node.value());
+                    existing.set_value(merged);
+                }
+                None => {
+                    let parent_key = node
+                        .parent()
+                        .map_or_else(|| self.root().key().clone(), |parent| parent.key().clone());
+                    // This only fails when the parent is full (see `with_max_children`), in
+                    // which case the branch is skipped along with its descendants
+                    let _ = self.insert(&parent_key, node.key().clone(), node.value().clone());
+                }
+            }
+        }
+    }
+
+    // Pair this tree with a structurally identical one: same keys, same parents, and same
+    // child order. The result is a new tree whose values are `(ours, theirs)` pairs, or None
+    // if the two trees differ anywhere
+    pub fn zip<U: Clone>(
+        &self,
+        other: &MultiIndexedTree<K, U>,
+    ) -> Option<MultiIndexedTree<K, (T, U)>> {
+        let root = self.root();
+        let other_root = other.root();
+        if *root.key() != *other_root.key() {
+            return None;
+        }
+
+        let zipped = MultiIndexedTree::new(
+            root.key().clone(),
+            (root.value().clone(), other_root.value().clone()),
+        );
+        let mut stack = vec![(root, other_root)];
+        while let Some((ours, theirs)) = stack.pop() {
+            let our_children = ours.children.borrow().clone();
+            let their_children = theirs.children.borrow().clone();
+            if our_children.len() != their_children.len() {
+                return None;
+            }
+
+            for (our_child, their_child) in our_children.into_iter().zip(their_children) {
+                if *our_child.key() != *their_child.key() {
+                    return None;
+                }
+                let value = (our_child.value().clone(), their_child.value().clone());
+                zipped
+                    .insert(&ours.key(), our_child.key().clone(), value)
+                    .ok()?;
+                stack.push((our_child, their_child));
+            }
+        }
+        Some(zipped)
+    }
+
+    // Map every node to the spread of its children's subtree heights (tallest minus shortest),
+    // with 0 for leaves and single-child nodes. Large values show where the tree is lopsided;
+    // `is_balanced` holds exactly when no factor exceeds 1
+    pub fn balance_factors(&self) -> HashMap<K, i64> {
+        let mut heights: HashMap<K, usize> = HashMap::new();
+        let mut factors = HashMap::new();
+
+        // Reversed pre-order visits every child before its parent
+        let nodes: Vec<_> = self.iter_depth_first().collect();
+        for node in nodes.iter().rev() {
+            let child_heights: Vec<usize> = node
+                .children
+                .borrow()
+                .iter()
+                .map(|child| heights[&*child.key()])
+                .collect();
+            let tallest = child_heights.iter().copied().max();
+            let shortest = child_heights.iter().copied().min();
+
+            let key = node.key().clone();
+            factors.insert(
+                key.clone(),
+                tallest.unwrap_or(0) as i64 - shortest.unwrap_or(0) as i64,
+            );
+            heights.insert(key, tallest.map_or(0, |height| height + 1));
+        }
+        factors
+    }
+
+    // Count the structurally distinct subtrees, one subtree per node. Two subtrees have the
+    // same shape when their roots have the same number of children and the children, taken in
+    // order, pairwise have the same shape; keys and values are ignored. So every leaf shares
+    // one shape, and a node with children [leaf, chain] differs from one with [chain, leaf]
+    pub fn distinct_subtree_shapes(&self) -> usize {
+        self.subtree_shape_ids()
+            .values()
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    // Give every node an id such that two nodes share an id exactly when their subtrees have
+    // the same shape (see `distinct_subtree_shapes`). Ids are interned, so there are no collisions
+    fn subtree_shape_ids(&self) -> HashMap<*const Node<K, T>, usize> {
+        let mut shapes: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut ids = HashMap::new();
+
+        // Reversed pre-order visits every child before its parent
+        let nodes: Vec<_> = self.iter_depth_first().collect();
+        for node in nodes.iter().rev() {
+            let signature: Vec<usize> = node
+                .children
+                .borrow()
+                .iter()
+                .map(|child| ids[&Rc::as_ptr(child)])
+                .collect();
+            let next_id = shapes.len();
+            let id = *shapes.entry(signature).or_insert(next_id);
+            ids.insert(Rc::as_ptr(node), id);
+        }
+        ids
+    }
+
+    // Check whether the tree has degenerated into a single chain, i.e. no node has more than
+    // one child. A lone root counts as a path
+    pub fn is_path(&self) -> bool {
+        self.iter_depth_first()
+            .all(|node| node.children.borrow().len() <= 1)
+    }
+
+    // Check whether, at every node, the heights of its child subtrees differ by at most 1.
+    // Only existing children are compared, so a node with a single child never violates this
+    pub fn is_balanced(&self) -> bool {
+        // Post-order height computation; None signals a violation somewhere in the subtree
+        fn balanced_height<K, T>(node: &Rc<Node<K, T>>) -> Option<usize>
+        where
+            K: Eq + Hash,
+        {
+            if node.is_leaf() {
+                return Some(0);
+            }
+
+            let mut min_height = usize::MAX;
+            let mut max_height = 0;
+            for child in node.children.borrow().iter() {
+                let height = balanced_height(child)?;
+                min_height = min(min_height, height);
+                max_height = max(max_height, height);
+            }
+
+            if max_height - min_height > 1 {
+                None
+            } else {
+                Some(max_height + 1)
+            }
+        }
+
+        balanced_height(&self.root()).is_some()
+    }
+
+    // Stream the tree to `writer` as tab-separated records, one node per line, without
+    // building the whole output in memory. Records follow depth-first pre-order so every
+    // parent precedes its children: the first line is `key<TAB>value` for the root, and every
+    // other line is `parent<TAB>key<TAB>value`. Backslashes, tabs and line breaks inside a
+    // field are escaped as `\\`, `\t`, `\n` and `\r`
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()>
+    where
+        K: Display,
+        T: Display,
+    {
+        let mut writer = BufWriter::new(writer);
+        for node in self.iter_depth_first() {
+            if let Some(parent) = node.parent() {
+                write!(writer, "{}\t", escape_field(&parent.key().to_string()))?;
+            }
+            writeln!(
+                writer,
+                "{}\t{}",
+                escape_field(&node.key().to_string()),
+                escape_field(&node.value().to_string())
+            )?;
+        }
+        writer.flush()
+    }
+
+    // Rebuild a tree from the record stream produced by `write_to`, one line at a time
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Self>
+    where
+        K: FromStr,
+        T: FromStr,
+    {
+        fn parse<V: FromStr>(field: &str) -> io::Result<V> {
+            unescape_field(field)?
+                .parse()
+                .map_err(|_| invalid_data(format!("could not parse field: {}", field)))
+        }
+
+        let mut lines = BufReader::new(reader).lines();
+        let root_line = lines
+            .next()
+            .ok_or_else(|| invalid_data("missing root record".to_string()))??;
+        let tree = match root_line.split('\t').collect::<Vec<_>>().as_slice() {
+            [key, value] => Self::new(parse(key)?, parse(value)?),
+            _ => {
+                return Err(invalid_data(format!(
+                    "malformed root record: {}",
+                    root_line
+                )))
+            }
+        };
+
+        for line in lines {
+            let line = line?;
+            match line.split('\t').collect::<Vec<_>>().as_slice() {
+                [parent, key, value] => {
+                    let key = parse(key)?;
+                    if tree.lookup(&key).is_some() {
+                        return Err(invalid_data(format!("duplicate key in record: {}", line)));
+                    }
+                    tree.insert(&parse(parent)?, key, parse(value)?)
+                        .map_err(invalid_data)?
+                }
+                _ => return Err(invalid_data(format!("malformed record: {}", line))),
+            }
+        }
+
+        Ok(tree)
+    }
+
+    // Map every key to the number of leaves in its subtree (a leaf maps to 1). Computed
+    // iteratively by walking the pre-order listing backwards, so children are always done
+    // before their parent and deep trees cannot overflow the stack
+    pub fn subtree_leaf_counts(&self) -> HashMap<K, usize> {
+        let nodes: Vec<_> = self.iter_depth_first().collect();
+        let mut counts: HashMap<K, usize> = HashMap::with_capacity(nodes.len());
+
+        for node in nodes.iter().rev() {
+            let count = if node.is_leaf() {
+                1
+            } else {
+                node.children
+                    .borrow()
+                    .iter()
+                    .map(|child| counts[&*child.key()])
+                    .sum()
+            };
+            counts.insert(node.key().clone(), count);
+        }
+
+        counts
+    }
+
+    // Count the nodes that have no children
+    pub fn leaf_count(&self) -> usize {
+        self.iter_depth_first()
+            .filter(|node| node.is_leaf())
+            .count()
+    }
+
+    // Count the nodes that have at least one child
+    pub fn internal_node_count(&self) -> usize {
+        self.iter_depth_first()
+            .filter(|node| !node.is_leaf())
+            .count()
+    }
+
+    // Count the parent -> child edges reachable from the root by walking the tree, not by
+    // trusting the index. In a consistent tree this is one less than the number of indexed
+    // nodes, so comparing the two is a cheap sanity check on the index
+    pub fn edge_count(&self) -> usize {
+        self.iter_depth_first()
+            .map(|node| node.children.borrow().len())
+            .sum()
+    }
+
+    // Tally the nodes by the group key `group_by` computes for each, e.g. by value category or
+    // by number of children. Groups with no nodes are absent from the result
+    pub fn group_count<G: Eq + Hash, F: Fn(&Rc<Node<K, T>>) -> G>(
+        &self,
+        group_by: F,
+    ) -> HashMap<G, usize> {
+        let mut counts = HashMap::new();
+        for node in self.iter_depth_first() {
+            *counts.entry(group_by(&node)).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    // Count the distinct depth levels in the tree (the height plus one); a lone root has 1
+    pub fn level_count(&self) -> usize {
+        self.width_profile().len()
+    }
+
+    // Get the depth of the shallowest leaf, i.e. the fewest edges from the root to any leaf.
+    // The breadth-first search stops at the first leaf; a lone root gives 0
+    pub fn min_leaf_depth(&self) -> usize {
+        let mut queue = VecDeque::from([(self.root(), 0)]);
+        while let Some((node, depth)) = queue.pop_front() {
+            if node.is_leaf() {
+                return depth;
+            }
+            for child in node.children.borrow().iter() {
+                queue.push_back((child.clone(), depth + 1));
+            }
+        }
+        unreachable!("a finite tree always has a leaf")
+    }
+
+    // Get every node exactly `depth` edges below the root, left to right. The level-by-level
+    // walk stops at the target depth, so deeper levels are never visited
+    pub fn nodes_at_depth(&self, depth: usize) -> Vec<Rc<Node<K, T>>> {
+        let mut frontier = vec![self.root()];
+        for _ in 0..depth {
+            if frontier.is_empty() {
+                break;
+            }
+            frontier = frontier
+                .iter()
+                .flat_map(|node| node.children.borrow().clone())
+                .collect();
+        }
+        frontier
+    }
+
+    // List the nodes level by level in zigzag order: left to right on even depths (starting
+    // with the root at depth 0) and right to left on odd depths
+    pub fn iter_zigzag(&self) -> Vec<Rc<Node<K, T>>> {
+        let mut nodes = Vec::new();
+        let mut frontier = vec![self.root()];
+        let mut reversed = false;
+        while !frontier.is_empty() {
+            if reversed {
+                nodes.extend(frontier.iter().rev().cloned());
+            } else {
+                nodes.extend(frontier.iter().cloned());
+            }
+            reversed = !reversed;
+            frontier = frontier
+                .iter()
+                .flat_map(|node| node.children.borrow().clone())
+                .collect();
+        }
+        nodes
+    }
+
+    // Map every key to its number of ancestors, i.e. its depth, in one breadth-first pass.
+    // Cheaper than calling `Node::depth` per node when all depths are needed; the root maps to 0
+    pub fn ancestor_counts(&self) -> HashMap<K, usize> {
+        let mut counts = HashMap::new();
+        let mut queue = VecDeque::from([(self.root(), 0)]);
+        while let Some((node, depth)) = queue.pop_front() {
+            for child in node.children.borrow().iter() {
+                queue.push_back((child.clone(), depth + 1));
+            }
+            counts.insert(node.key().clone(), depth);
+        }
+        counts
+    }
+
+    // Count the nodes at each depth: entry `i` is the number of nodes `i` edges below the root
+    pub fn width_profile(&self) -> Vec<usize> {
+        let mut widths = Vec::new();
+        let mut frontier = vec![self.root()];
+        while !frontier.is_empty() {
+            widths.push(frontier.len());
+            frontier = frontier
+                .iter()
+                .flat_map(|node| node.children.borrow().clone())
+                .collect();
+        }
+        widths
+    }
+
+    // Count the downward (ancestor-to-descendant) paths with exactly `length` edges. Paths may
+    // start at any node, not just the root; a zero-length path is a single node. Each such path
+    // is fixed by its bottom node, so this is the number of nodes at depth `length` or deeper
+    pub fn count_paths_of_length(&self, length: usize) -> usize {
+        self.width_profile().iter().skip(length).sum()
+    }
+
+    // Compute the mean number of children over internal (non-leaf) nodes only, so leaves do
+    // not drag the average down. Returns 0.0 when the tree is a lone root
+    pub fn average_branching_factor(&self) -> f64 {
+        let mut internal_nodes = 0;
+        let mut total_children = 0;
+        for node in self.iter_depth_first() {
+            let children = node.children.borrow().len();
+            if children > 0 {
+                internal_nodes += 1;
+                total_children += children;
+            }
+        }
+
+        if internal_nodes == 0 {
+            0.0
+        } else {
+            total_children as f64 / internal_nodes as f64
+        }
+    }
+
+    // Find the node with the most children. Ties go to whichever comes first in depth-first
+    // pre-order, so a lone root is its own widest node
+    pub fn widest_node(&self) -> Rc<Node<K, T>> {
+        let mut widest = self.root();
+        let mut widest_children = widest.children.borrow().len();
+        for node in self.iter_depth_first() {
+            let children = node.children.borrow().len();
+            if children > widest_children {
+                widest_children = children;
+                widest = node;
+            }
+        }
+        widest
+    }
+
+    // Encode the tree in a compact, self-describing binary format. The layout is stable:
+    //
+    //   magic     4 bytes   b"MITB"
+    //   version   1 byte    currently 1
+    //   count     u64 LE    number of node records
+    //   records   count x { parent: u64 LE, key_len: u64 LE, key, value_len: u64 LE, value }
+    //
+    // Records are in depth-first pre-order, so the root comes first. `parent` is the position
+    // of the parent's record (u64::MAX for the root), and keys and values are the bytes
+    // produced by their `Into<Vec<u8>>` conversions
+    pub fn to_bytes(&self) -> Vec<u8>
+    where
+        K: Into<Vec<u8>>,
+        T: Into<Vec<u8>>,
+    {
+        fn push_chunk(bytes: &mut Vec<u8>, chunk: Vec<u8>) {
+            bytes.extend_from_slice(&(chunk.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&chunk);
+        }
+
+        let nodes: Vec<_> = self.iter_depth_first().collect();
+        let mut positions: HashMap<K, u64> = HashMap::with_capacity(nodes.len());
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(BINARY_MAGIC);
+        bytes.push(BINARY_VERSION);
+        bytes.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+
+        for (position, node) in nodes.iter().enumerate() {
+            let parent = node
+                .parent()
+                .map_or(u64::MAX, |parent| positions[&*parent.key()]);
+            positions.insert(node.key().clone(), position as u64);
+
+            bytes.extend_from_slice(&parent.to_le_bytes());
+            push_chunk(&mut bytes, node.key().clone().into());
+            push_chunk(&mut bytes, node.value().clone().into());
+        }
+
+        bytes
+    }
+
+    // Decode a tree from the binary format written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self>
+    where
+        K: TryFrom<Vec<u8>>,
+        T: TryFrom<Vec<u8>>,
+    {
+        // Split `len` bytes off the front of `bytes`
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+            if bytes.len() < len {
+                return Err(invalid_data("unexpected end of input".to_string()));
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+
+        fn take_u64(bytes: &mut &[u8]) -> io::Result<u64> {
+            let mut buffer = [0; 8];
+            buffer.copy_from_slice(take(bytes, 8)?);
+            Ok(u64::from_le_bytes(buffer))
+        }
+
+        fn take_chunk<V: TryFrom<Vec<u8>>>(bytes: &mut &[u8]) -> io::Result<V> {
+            let len = usize::try_from(take_u64(bytes)?)
+                .map_err(|_| invalid_data("chunk length overflow".to_string()))?;
+            V::try_from(take(bytes, len)?.to_vec())
+                .map_err(|_| invalid_data("could not decode key or value".to_string()))
+        }
+
+        let mut bytes = bytes;
+        if take(&mut bytes, BINARY_MAGIC.len())? != BINARY_MAGIC {
+            return Err(invalid_data("not a serialized tree".to_string()));
+        }
+        let version = take(&mut bytes, 1)?[0];
+        if version != BINARY_VERSION {
+            return Err(invalid_data(format!("unsupported version: {}", version)));
+        }
+
+        let count = take_u64(&mut bytes)?;
+        if count == 0 || take_u64(&mut bytes)? != u64::MAX {
+            return Err(invalid_data("missing root record".to_string()));
+        }
+        let root_key: K = take_chunk(&mut bytes)?;
+        let tree = Self::new(root_key.clone(), take_chunk(&mut bytes)?);
+
+        // Keys by record position, for resolving parent references
+        let mut keys = vec![root_key];
+        for position in 1..count {
+            let parent = take_u64(&mut bytes)?;
+            let parent_key = usize::try_from(parent)
+                .ok()
+                .and_then(|parent| keys.get(parent))
+                .ok_or_else(|| invalid_data(format!("invalid parent position: {}", parent)))?
+                .clone();
+            let key: K = take_chunk(&mut bytes)?;
+            if tree.lookup(&key).is_some() {
+                return Err(invalid_data(format!(
+                    "duplicate key in record {}",
+                    position
+                )));
+            }
+            tree.insert(&parent_key, key.clone(), take_chunk(&mut bytes)?)
+                .map_err(invalid_data)?;
+            keys.push(key);
+        }
+
+        if !bytes.is_empty() {
+            return Err(invalid_data("trailing bytes after last record".to_string()));
+        }
+
+        Ok(tree)
+    }
+
+    // Encode the tree as a binary tree using the left-child/right-sibling representation: each
+    // node's first child becomes its `left` pointer and its next sibling its `right` pointer
+    pub fn to_binary(&self) -> BinaryNode<K, T> {
+        fn encode<K, T>(node: &Rc<Node<K, T>>) -> BinaryNode<K, T>
+        where
+            K: Eq + Hash + Clone,
+            T: Clone,
+        {
+            // Build the sibling chain back to front so only the depth recurses
+            let mut left = None;
+            for child in node.children.borrow().iter().rev() {
+                let mut encoded = encode(child);
+                encoded.right = left.take();
+                left = Some(Box::new(encoded));
+            }
+
+            BinaryNode {
+                key: node.key().clone(),
+                value: node.value().clone(),
+                left,
+                right: None,
+            }
+        }
+
+        encode(&self.root())
+    }
+
+    // Rebuild a tree from its left-child/right-sibling encoding (see `to_binary`). Fails if
+    // the root has a `right` sibling or if a key appears more than once
+    pub fn from_binary(root: &BinaryNode<K, T>) -> Result<Self, TreeError<K>> {
+        if let Some(sibling) = &root.right {
+            return Err(TreeError::MultipleRoots(sibling.key.clone()));
+        }
+
+        let tree = Self::new(root.key.clone(), root.value.clone());
+        let mut stack = vec![(&root.key, root.left.as_deref())];
+        while let Some((parent_key, first_child)) = stack.pop() {
+            let mut current = first_child;
+            while let Some(node) = current {
+                if tree.lookup(&node.key).is_some() {
+                    return Err(TreeError::DuplicateKey(node.key.clone()));
+                }
+                tree.insert(parent_key, node.key.clone(), node.value.clone())
+                    .map_err(|_| TreeError::KeyNotFound(parent_key.clone()))?;
+                stack.push((&node.key, node.left.as_deref()));
+                current = node.right.as_deref();
+            }
+        }
+
+        Ok(tree)
+    }
+
+    // Export the tree as an indented JSON array of `{depth, key, parent, value}` records in
+    // depth-first pre-order, so the root comes first and every parent precedes its children.
+    // `parent` is null for the root. Read back with `from_flat_json`. Panics if a key's or
+    // value's `Serialize` impl fails
+    #[cfg(feature = "serde")]
+    pub fn to_flat_json(&self) -> String
+    where
+        K: Serialize,
+        T: Serialize,
+    {
+        Self::flat_json_from(&self.root())
+    }
+
+    // Export just the subtree rooted at the given key, in the same format as `to_flat_json`
+    // but as if that node were the root: depths are relative to it and its `parent` is null,
+    // so `from_flat_json` rebuilds the branch as a standalone tree
+    #[cfg(feature = "serde")]
+    pub fn subtree_to_json(&self, key: &K) -> Option<String>
+    where
+        K: Serialize,
+        T: Serialize,
+    {
+        Some(Self::flat_json_from(&self.lookup(key)?))
+    }
+
+    // Serialize the subtree below `root` as flat JSON records, treating `root` as parentless
+    #[cfg(feature = "serde")]
+    fn flat_json_from(root: &Rc<Node<K, T>>) -> String
+    where
+        K: Serialize,
+        T: Serialize,
+    {
+        let mut records = Vec::new();
+        let mut stack = vec![(root.clone(), None, 0)];
+        while let Some((node, parent, depth)) = stack.pop() {
+            let key = node.key().clone();
+            for child in node.children.borrow().iter().rev() {
+                stack.push((child.clone(), Some(key.clone()), depth + 1));
+            }
+            records.push(FlatRecord {
+                depth,
+                key,
+                parent,
+                value: node.value().clone(),
+            });
+        }
+
+        serde_json::to_string_pretty(&records).expect("keys and values serialize to JSON")
+    }
+
+    // Rebuild a tree from the output of `to_flat_json`. Records must list every parent before
+    // its children; siblings keep their record order. The first record is the root, and each
+    // `depth` must be one more than its parent's
+    #[cfg(feature = "serde")]
+    pub fn from_flat_json(json: &str) -> Result<Self, TreeError<K>>
+    where
+        K: DeserializeOwned,
+        T: DeserializeOwned,
+    {
+        let records: Vec<FlatRecord<K, T>> =
+            serde_json::from_str(json).map_err(|err| TreeError::Syntax(err.to_string()))?;
+        let mut records = records.into_iter();
+        let root = records
+            .next()
+            .ok_or_else(|| TreeError::Syntax("no records".to_string()))?;
+        if root.parent.is_some() || root.depth != 0 {
+            return Err(TreeError::Inconsistent(root.key));
+        }
+
+        let tree = Self::new(root.key.clone(), root.value);
+        let mut depths = HashMap::from([(root.key, 0)]);
+        for record in records {
+            let parent = record
+                .parent
+                .ok_or_else(|| TreeError::MultipleRoots(record.key.clone()))?;
+            let parent_depth = *depths
+                .get(&parent)
+                .ok_or_else(|| TreeError::KeyNotFound(parent.clone()))?;
+            if depths.contains_key(&record.key) {
+                return Err(TreeError::DuplicateKey(record.key));
+            }
+            if record.depth != parent_depth + 1 {
+                return Err(TreeError::Inconsistent(record.key));
+            }
+
+            let _ = tree.insert(&parent, record.key.clone(), record.value);
+            depths.insert(record.key, record.depth);
+        }
+        Ok(tree)
+    }
+
+    // Render the tree as nested XML elements named `tag`, one per line and indented by depth.
+    // Each element carries the node's key in a `key` attribute and its value as leading text;
+    // children are nested inside their parent's element. `tag` is emitted as-is, so it must be
+    // a valid XML name
+    pub fn to_xml(&self, tag: &str) -> String
+    where
+        K: Display,
+        T: Display,
+    {
+        fn write_element<K, T>(node: &Rc<Node<K, T>>, tag: &str, depth: usize, out: &mut String)
+        where
+            K: Eq + Hash + Display,
+            T: Display,
+        {
+            let indent = "  ".repeat(depth);
+            out.push_str(&format!(
+                "{}<{} key=\"{}\">{}",
+                indent,
+                tag,
+                escape_xml(&node.key().to_string()),
+                escape_xml(&node.value().to_string())
+            ));
+
+            if node.is_leaf() {
+                out.push_str(&format!("</{}>\n", tag));
+                return;
+            }
+
+            out.push('\n');
+            for child in node.children.borrow().iter() {
+                write_element(child, tag, depth + 1, out);
+            }
+            out.push_str(&format!("{}</{}>\n", indent, tag));
+        }
+
+        let mut xml = String::new();
+        write_element(&self.root(), tag, 0, &mut xml);
+        xml
+    }
+
+    // Check the tree's invariants: the root has no parent, every reachable node is indexed
+    // under its own key, every child points back at its parent and records its true position,
+    // and the index holds nothing that is unreachable. Reports the first offending key
+    pub fn validate(&self) -> Result<(), TreeError<K>> {
+        let root = self.root();
+        if !root.is_root() {
+            return Err(TreeError::Inconsistent(root.key().clone()));
+        }
+
+        let index = self.index.borrow();
+        let mut reachable = 0;
+        for node in self.iter_depth_first() {
+            reachable += 1;
+            let key = node.key().clone();
+            if !index
+                .get(&key)
+                .is_some_and(|indexed| Rc::ptr_eq(indexed, &node))
+            {
+                return Err(TreeError::Inconsistent(key));
+            }
+
+            for (position, child) in node.children.borrow().iter().enumerate() {
+                let linked = child
+                    .parent()
+                    .is_some_and(|parent| Rc::ptr_eq(&parent, &node));
+                if !linked || *child.index.borrow() != position {
+                    return Err(TreeError::Inconsistent(child.key().clone()));
+                }
+            }
+        }
+
+        if index.len() != reachable {
+            let orphaned = self.orphaned_keys().into_iter().next();
+            return Err(TreeError::Inconsistent(
+                orphaned.unwrap_or_else(|| root.key().clone()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Repair pass: rewrite every child's `index` field to match its actual position in its
+    // parent's children vector. Run after suspected corruption; it fixes stale positions only,
+    // not broken parent links or index entries
+    pub fn reindex_children(&self) {
+        for node in self.iter_depth_first() {
+            node.reindex_children_from(0);
+        }
+    }
+
+    // Fast connectivity check: the root has no parent and every indexed node is reachable
+    // from it. A subset of `validate`, which also checks sibling positions and back-links
+    pub fn is_connected(&self) -> bool {
+        self.root().is_root() && self.orphaned_keys().is_empty()
+    }
+
+    // Diagnostic: list index keys whose node can no longer be reached from the root, e.g. a
+    // node left behind by a partially-failed operation. Sorted by key
+    pub fn orphaned_keys(&self) -> Vec<K> {
+        let reachable: HashSet<*const Node<K, T>> = self
+            .iter_depth_first()
+            .map(|node| Rc::as_ptr(&node))
+            .collect();
+
+        let mut orphaned: Vec<K> = self
+            .index
+            .borrow()
+            .iter()
+            .filter(|(_, node)| !reachable.contains(&Rc::as_ptr(node)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        orphaned.sort();
+        orphaned
+    }
+
+    // Find all nodes under a tag, ordered by depth-first (pre-order) traversal rather than by
+    // when they were tagged
+    pub fn iter_tagged_in_order(&self, tag: &str) -> Vec<Rc<Node<K, T>>> {
+        let tagged: HashSet<K> = match self.secondary_index.borrow().get(tag) {
+            Some(keys) => keys.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+
+        self.iter_depth_first()
+            .filter(|node| tagged.contains(&*node.key()))
+            .collect()
+    }
+
+    // Snapshot the whole secondary index as tag -> live nodes, skipping keys whose nodes no
+    // longer exist
+    pub fn all_tagged_nodes(&self) -> HashMap<String, Vec<Rc<Node<K, T>>>> {
+        let tags: Vec<String> = self.secondary_index.borrow().keys().cloned().collect();
+        tags.into_iter()
+            .filter_map(|tag| {
+                let nodes = self.find_by_secondary_index(&tag)?;
+                Some((tag, nodes))
+            })
+            .collect()
+    }
+
+    // Drop secondary-index entries for keys that no longer exist in the primary index, then
+    // remove any tag left with an empty bucket. Bucket order is preserved
+    pub fn compact_secondary_index(&self) {
+        let index = self.index.borrow();
+        let mut secondary_index = self.secondary_index.borrow_mut();
+        for keys in secondary_index.values_mut() {
+            keys.retain(|key| index.contains_key(key));
+        }
+        secondary_index.retain(|_, keys| !keys.is_empty());
+    }
+
+    // Debug helper: the `Rc` strong count of a node, not counting the ordered index's own
+    // handle (see `enable_ordered_index`). A node held only by the tree then always has a count
+    // of 2 (its index entry plus its parent's children list, or the tree's root slot); anything
+    // higher means handles are still alive elsewhere
+    #[cfg(feature = "debug")]
+    pub fn strong_count(&self, key: &K) -> Option<usize> {
+        self.index
+            .borrow()
+            .get(key)
+            .map(|node| self.tree_strong_count(node))
+    }
+
+    // Debug helper: `strong_count` for every indexed node
+    #[cfg(feature = "debug")]
+    pub fn audit_refcounts(&self) -> HashMap<K, usize> {
+        self.index
+            .borrow()
+            .iter()
+            .map(|(key, node)| (key.clone(), self.tree_strong_count(node)))
+            .collect()
+    }
+
+    // The strong count of an indexed node, minus the ordered index's handle if it is enabled
+    #[cfg(feature = "debug")]
+    fn tree_strong_count(&self, node: &Rc<Node<K, T>>) -> usize {
+        let ordered = usize::from(self.ordered_index.borrow().is_some());
+        Rc::strong_count(node) - ordered
+    }
+
+    // Create a depth-first iterator for the tree
+    pub fn iter_depth_first(&self) -> DepthFirstIterator<K, T> {
+        DepthFirstIterator {
+            stack: vec![self.root()],
+        }
+    }
+
+    // Create a breadth-first iterator for the tree
+    pub fn iter_breadth_first(&self) -> BreadthFirstIterator<K, T> {
+        BreadthFirstIterator {
+            queue: VecDeque::from(vec![self.root()]),
+        }
+    }
+
+    // Create a post-order iterator for the tree: every node comes after all of its
+    // descendants, so the root is yielded last
+    pub fn iter_post_order(&self) -> PostOrderIterator<K, T> {
+        PostOrderIterator {
+            stack: vec![(self.root(), false)],
+        }
+    }
+
+    // Create a post-order iterator over the subtree rooted at the given key, or `None` if the
+    // key is missing. Useful for bottom-up aggregation over one branch; the node itself comes last
+    pub fn iter_subtree_post_order(&self, key: &K) -> Option<PostOrderIterator<K, T>> {
+        Some(PostOrderIterator {
+            stack: vec![(self.lookup(key)?, false)],
+        })
+    }
+
+    // Create a breadth-first iterator over the subtree rooted at the given key, or `None` if
+    // the key is missing. The level-order counterpart to `subtree_view(key)?.iter_depth_first()`
+    pub fn iter_subtree_breadth_first(&self, key: &K) -> Option<BreadthFirstIterator<K, T>> {
+        Some(BreadthFirstIterator {
+            queue: VecDeque::from(vec![self.lookup(key)?]),
+        })
+    }
+
+    // Create a depth-first (pre-order) iterator that yields each node with its parent
+    pub fn iter_with_parent(&self) -> ParentIterator<K, T> {
+        ParentIterator {
+            stack: vec![(None, self.root())],
+        }
+    }
+
+    // Iterate over the root-to-node key path of every node, joined by `sep`
+    // (e.g. `root/child1/child1.1`). Nodes are visited in depth-first pre-order
+    pub fn iter_paths<'a>(&self, sep: &'a str) -> impl Iterator<Item = String> + 'a
+    where
+        K: Display + 'a,
+        T: 'a,
+    {
+        let mut stack = vec![(self.root().key().to_string(), self.root())];
+        std::iter::from_fn(move || {
+            let (path, node) = stack.pop()?;
+            for child in node.children.borrow().iter().rev() {
+                stack.push((format!("{}{}{}", path, sep, child.key()), child.clone()));
+            }
+            Some(path)
+        })
+    }
+
+    // Lazily yield the nodes matching `predicate` during a depth-first pre-order walk. Matches
+    // are produced one at a time rather than collected, so memory stays bounded by the walk's
+    // frontier however many nodes match
+    pub fn find_all_lazy<'a, F: Fn(&Rc<Node<K, T>>) -> bool + 'a>(
+        &'a self,
+        predicate: F,
+    ) -> impl Iterator<Item = Rc<Node<K, T>>> + 'a {
+        self.iter_depth_first().filter(move |node| predicate(node))
+    }
+
+    // Find every node whose value lies in the inclusive range `[low, high]`, in depth-first
+    // pre-order. Values that are incomparable with a bound (e.g. NaN) never match. This scans
+    // the whole tree; only keys have an ordered index (see `range`)
+    pub fn find_values_in_range(&self, low: &T, high: &T) -> Vec<Rc<Node<K, T>>>
+    where
+        T: PartialOrd,
+    {
+        self.iter_depth_first()
+            .filter(|node| {
+                let value = node.value();
+                *low <= *value && *value <= *high
+            })
+            .collect()
+    }
+
+    // Iterate over every node in ascending key order, independent of the tree's shape. The
+    // nodes are snapshotted up front, from the ordered index when enabled or by sorting
+    pub fn iter_sorted(&self) -> impl Iterator<Item = Rc<Node<K, T>>> {
+        let nodes: Vec<_> = match self.ordered_index.borrow().as_ref() {
+            Some(ordered) => ordered.values().cloned().collect(),
+            None => {
+                let mut nodes: Vec<_> = self.index.borrow().values().cloned().collect();
+                nodes.sort_by(|a, b| a.key().cmp(&b.key()));
+                nodes
+            }
+        };
+        nodes.into_iter()
+    }
+
+    // List every key in topological order: each parent appears before all of its children
+    // (this is a breadth-first listing). Guarantee: replaying the list, inserting each key after
+    // the first under its parent's key, always succeeds and rebuilds the same shape with the
+    // same sibling order, because every parent has already been inserted by the time a child
+    // is reached
+    pub fn topological_order(&self) -> Vec<K> {
+        self.iter_breadth_first()
+            .map(|node| node.key().clone())
+            .collect()
+    }
+
+    // Get a lightweight read-only view of the subtree rooted at the given key. Nothing is
+    // copied: the view shares the tree's nodes
+    pub fn subtree_view(&self, key: &K) -> Option<SubtreeView<K, T>> {
+        Some(SubtreeView {
+            root: self.lookup(key)?,
+        })
+    }
+
+    // Create a shortest path iterator
+    pub fn iter_shortest_path(&self) -> ShortestPathIterator<K, T> {
+        let mut queue = VecDeque::new();
+        queue.push_back((0, self.root())); // Start with the root at depth 0
+        ShortestPathIterator { queue }
+    }
+
+    // Implement Dijkstra's algorithm to find all shortest paths from start to end
+    pub fn dijkstra_shortest_paths(
+        &self,
+        start_key: &K,
+        end_key: &K,
+    ) -> Option<HashMap<usize, Vec<K>>> {
+        let mut distances: HashMap<K, usize> = HashMap::new();
+        let mut predecessors: HashMap<K, Vec<K>> = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        // Initialize distances
+        for key in self.index.borrow().keys() {
+            distances.insert(key.clone(), usize::MAX);
+        }
+
+        // Set the start node's distance to 0
+        distances.insert(start_key.clone(), 0);
+        queue.push_back(start_key.clone());
+
+        // Perform the BFS-style traversal
+        while let Some(current_key) = queue.pop_front() {
+            let current_distance = *distances.get(&current_key).unwrap();
+            if let Some(node) = self.index.borrow().get(&current_key) {
+                for child in node.children.borrow().iter() {
+                    let child_key = child.key().clone();
+                    let new_distance = current_distance + 1; // Assuming unweighted edges (weight = 1)
+
+                    if new_distance < *distances.get(&child_key).unwrap() {
+                        distances.insert(child_key.clone(), new_distance);
+                        predecessors.insert(child_key.clone(), vec![current_key.clone()]);
+                        queue.push_back(child_key.clone());
+                    } else if new_distance == *distances.get(&child_key).unwrap() {
+                        // Add an additional predecessor for ties in shortest paths
+                        predecessors
+                            .entry(child_key.clone())
+                            .or_default()
+                            .push(current_key.clone());
+                    }
+                }
+            }
+        }
+
+        // Trace back from the end node to gather paths
+        fn trace_paths<K: Clone + Eq + Hash>(
+            end_key: &K,
+            predecessors: &HashMap<K, Vec<K>>,
+            current_path: &mut Vec<K>,
+            all_paths: &mut HashMap<usize, Vec<K>>,
+        ) {
+            current_path.push(end_key.clone());
+            if let Some(preds) = predecessors.get(end_key) {
+                for pred in preds {
+                    trace_paths(pred, predecessors, current_path, all_paths);
+                }
+            } else {
+                // Base case: no predecessor, add reversed path
+                let mut path = current_path.clone();
+                path.reverse();
+                all_paths.insert(path.len(), path);
+            }
+            current_path.pop();
+        }
+
+        if distances[end_key] == usize::MAX {
+            return None; // No path exists
+        }
+
+        let mut all_paths = HashMap::new();
+        let mut current_path = Vec::new();
+        trace_paths(end_key, &predecessors, &mut current_path, &mut all_paths);
+
+        Some(all_paths)
+    }
+
+    // Build a petgraph directed graph mirroring the parent -> child edges,
+    // along with a map from each key to its petgraph node index
+    #[cfg(feature = "petgraph")]
+    pub fn to_petgraph(&self) -> (DiGraph<K, ()>, HashMap<K, NodeIndex>) {
+        let mut graph = DiGraph::new();
+        let mut indices = HashMap::new();
+
+        // Breadth-first order guarantees a parent is added before its children
+        for node in self.iter_breadth_first() {
+            let node_index = graph.add_node(node.key().clone());
+            if let Some(parent) = node.parent() {
+                graph.add_edge(indices[&*parent.key()], node_index, ());
+            }
+            indices.insert(node.key().clone(), node_index);
+        }
+
+        (graph, indices)
+    }
+}
+
+impl<K, V> MultiIndexedTree<K, Rc<V>>
+where
+    K: Eq + Hash + Ord + Clone,
+    V: Eq + Hash,
+{
+    // Find subtrees that are identical in shape and values (keys are ignored) and make every
+    // duplicate share the `Rc` payloads of the first such subtree in depth-first pre-order.
+    // Returns how many duplicate subtrees had payloads merged, counting only the outermost
+    // duplicate of a nested run; an immediate second call returns 0.
+    //
+    // Only the payloads are shared: nodes keep their own keys, parents and positions, because
+    // a node can have only one parent and one index entry. The tree stays a tree, so traversal
+    // and `remove` are unaffected. The memory saving comes from the values, and the sharing is
+    // visible through interior mutability in `V`: mutating one shared payload changes it for
+    // every node holding it, while `Node::set_value` replaces a single node's `Rc`. This is
+    // why deduplication only exists on `SharedValueTree` and only runs when called
+    pub fn deduplicate_subtrees(&self) -> usize {
+        let mut interned: HashMap<(Rc<V>, Vec<usize>), usize> = HashMap::new();
+        let mut ids = HashMap::new();
+
+        // Reversed pre-order visits every child before its parent
+        let nodes: Vec<_> = self.iter_depth_first().collect();
+        for node in nodes.iter().rev() {
+            let signature: Vec<usize> = node
+                .children
+                .borrow()
+                .iter()
+                .map(|child| ids[&Rc::as_ptr(child)])
+                .collect();
+            let next_id = interned.len();
+            let id = *interned
+                .entry((node.value().clone(), signature))
+                .or_insert(next_id);
+            ids.insert(Rc::as_ptr(node), id);
+        }
+
+        // The first subtree with a given id in pre-order is the canonical copy. Every node
+        // of a duplicate subtree is itself a duplicate, so it is redirected node by node
+        let mut canonical: HashMap<usize, Rc<Node<K, Rc<V>>>> = HashMap::new();
+        let mut outermost = HashMap::new(); // Node -> position of its outermost duplicate
+        let mut merged = HashSet::new();
+        for (position, node) in nodes.iter().enumerate() {
+            let id = ids[&Rc::as_ptr(node)];
+            let Some(original) = canonical.get(&id) else {
+                canonical.insert(id, node.clone());
+                continue;
+            };
+
+            let top = node
+                .parent()
+                .and_then(|parent| outermost.get(&Rc::as_ptr(&parent)).copied())
+                .unwrap_or(position);
+            outermost.insert(Rc::as_ptr(node), top);
+
+            let shared = original.value().clone();
+            if !Rc::ptr_eq(&node.value(), &shared) {
+                node.set_value(shared);
+                merged.insert(top);
+            }
+        }
+        merged.len()
+    }
+}
+
+impl MultiIndexedTree<String, ()> {
+    // Rebuild a tree from a simple DOT `digraph`: node statements (`a;`), edge statements and
+    // chains (`a -> b -> c;`), with identifiers optionally quoted. Attribute lists, graph
+    // attributes (`rankdir=LR`) and `//` comments are ignored; subgraphs are not supported.
+    // Children keep the order in which their edges appear. The root is the one node without an
+    // incoming edge; a node with two parents, a cycle, or a second root is an error
+    pub fn from_dot(dot: &str) -> Result<Self, TreeError<String>> {
+        let syntax = |message: &str| TreeError::Syntax(message.to_string());
+
+        let header_end = dot.find('{').ok_or_else(|| syntax("missing '{'"))?;
+        let body_end = dot.rfind('}').ok_or_else(|| syntax("missing '}'"))?;
+        let header: Vec<&str> = dot[..header_end].split_whitespace().collect();
+        if !matches!(
+            header.as_slice(),
+            ["digraph", ..] | ["strict", "digraph", ..]
+        ) {
+            return Err(syntax("expected a digraph"));
+        }
+        if body_end < header_end {
+            return Err(syntax("'}' before '{'"));
+        }
+
+        let tokens = dot_tokens(&dot[header_end + 1..body_end])?;
+        let mut nodes: Vec<String> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut edges: Vec<(String, String)> = Vec::new();
+
+        let mut position = 0;
+        while position < tokens.len() {
+            match &tokens[position] {
+                DotToken::Separator => position += 1,
+                DotToken::Id(id) => {
+                    // Graph attribute (`name = value`) or default attributes (`node [...]`)
+                    if tokens.get(position + 1) == Some(&DotToken::Equals) {
+                        match tokens.get(position + 2) {
+                            Some(DotToken::Id(_)) => position += 3,
+                            _ => return Err(syntax("expected a value after '='")),
+                        }
+                        continue;
+                    }
+                    if matches!(id.as_str(), "node" | "edge" | "graph")
+                        && tokens.get(position + 1) == Some(&DotToken::Attributes)
+                    {
+                        position += 2;
+                        continue;
+                    }
+
+                    let mut chain = vec![id.clone()];
+                    position += 1;
+                    while tokens.get(position) == Some(&DotToken::Arrow) {
+                        match tokens.get(position + 1) {
+                            Some(DotToken::Id(id)) => chain.push(id.clone()),
+                            _ => return Err(syntax("expected a node after '->'")),
+                        }
+                        position += 2;
+                    }
+                    if tokens.get(position) == Some(&DotToken::Attributes) {
+                        position += 1;
+                    }
+
+                    for id in chain.iter() {
+                        if seen.insert(id.clone()) {
+                            nodes.push(id.clone());
+                        }
+                    }
+                    for pair in chain.windows(2) {
+                        edges.push((pair[0].clone(), pair[1].clone()));
+                    }
+                }
+                DotToken::Arrow => return Err(syntax("'->' without a source node")),
+                DotToken::Equals => return Err(syntax("unexpected '='")),
+                DotToken::Attributes => return Err(syntax("attribute list without a statement")),
+            }
+        }
+
+        let mut parents: HashMap<String, String> = HashMap::new();
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for (parent, child) in edges {
+            if parent == child {
+                return Err(TreeError::CycleDetected(child));
+            }
+            if parents.contains_key(&child) {
+                return Err(TreeError::MultipleParents(child));
+            }
+            parents.insert(child.clone(), parent.clone());
+            children.entry(parent).or_default().push(child);
+        }
+
+        let mut roots = nodes.iter().filter(|node| !parents.contains_key(*node));
+        let root = match roots.next() {
+            Some(root) => root.clone(),
+            // Every node has a parent, so following parents must loop
+            None => match nodes.first() {
+                Some(node) => return Err(TreeError::CycleDetected(node.clone())),
+                None => return Err(syntax("the graph has no nodes")),
+            },
+        };
+        if let Some(extra) = roots.next() {
+            return Err(TreeError::MultipleRoots(extra.clone()));
+        }
+
+        let tree = MultiIndexedTree::new(root.clone(), ());
+        let mut queue = VecDeque::from([root]);
+        while let Some(parent) = queue.pop_front() {
+            for child in children.remove(&parent).unwrap_or_default() {
+                let _ = tree.insert(&parent, child.clone(), ());
+                queue.push_back(child);
+            }
+        }
+
+        // With a single root and one parent per node, anything unreached sits on a cycle
+        if let Some(node) = nodes.iter().find(|node| tree.lookup(node).is_none()) {
+            return Err(TreeError::CycleDetected(node.clone()));
+        }
+        Ok(tree)
+    }
+}
+
+// Result of comparing two trees with `MultiIndexedTree::diff`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeDiff<K> {
+    // Keys only present in the other tree
+    pub added: Vec<K>,
+    // Keys only present in this tree
+    pub removed: Vec<K>,
+    // Keys present in both trees with a different value or a different parent
+    pub changed: Vec<K>,
+}
+
+// One node in the flat JSON export (see `MultiIndexedTree::to_flat_json`)
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct FlatRecord<K, T> {
+    depth: usize,
+    key: K,
+    parent: Option<K>,
+    value: T,
+}
+
+// Leading bytes and format version of the binary encoding used by `to_bytes`
+const BINARY_MAGIC: &[u8; 4] = b"MITB";
+const BINARY_VERSION: u8 = 1;
+
+// Escape a field for the line-based format used by `write_to`
+fn escape_field(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// Reverse `escape_field`, rejecting unknown escape sequences
+fn unescape_field(field: &str) -> io::Result<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            _ => return Err(invalid_data(format!("invalid escape in field: {}", field))),
+        }
+    }
+    Ok(unescaped)
+}
+
+// Escape the characters that are significant in XML text and attribute values
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Lexical tokens of a DOT graph body, as read by `MultiIndexedTree::from_dot`
+#[derive(Debug, PartialEq)]
+enum DotToken {
+    Id(String),
+    Arrow,
+    Equals,
+    Separator,  // `;` or `,`
+    Attributes, // A whole `[...]` list, whose contents are ignored
+}
+
+// Split a DOT graph body into tokens, unquoting quoted identifiers and dropping comments
+fn dot_tokens(body: &str) -> Result<Vec<DotToken>, TreeError<String>> {
+    let syntax = |message: &str| TreeError::Syntax(message.to_string());
+    let mut tokens = Vec::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            ';' | ',' => tokens.push(DotToken::Separator),
+            '=' => tokens.push(DotToken::Equals),
+            '-' if chars.peek() == Some(&'>') => {
+                chars.next();
+                tokens.push(DotToken::Arrow);
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '[' => {
+                let mut in_quotes = false;
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => in_quotes = !in_quotes,
+                        '\\' if in_quotes => {
+                            chars.next();
+                        }
+                        ']' if !in_quotes => {
+                            closed = true;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                if !closed {
+                    return Err(syntax("unterminated attribute list"));
+                }
+                tokens.push(DotToken::Attributes);
+            }
+            '"' => {
+                let mut id = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            id.push('"');
+                        }
+                        c => id.push(c),
+                    }
+                }
+                if !closed {
+                    return Err(syntax("unterminated quoted identifier"));
+                }
+                tokens.push(DotToken::Id(id));
+            }
+            c if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' => {
+                let mut id = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                        break;
+                    }
+                    id.push(c);
+                    chars.next();
+                }
+                tokens.push(DotToken::Id(id));
+            }
+            '{' | '}' => return Err(syntax("subgraphs are not supported")),
+            c => return Err(TreeError::Syntax(format!("unexpected character: {:?}", c))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Read-only view of the subtree below one node, returned by `MultiIndexedTree::subtree_view`.
+// It holds the subtree root's `Rc`, so it observes later changes made through the tree
+pub struct SubtreeView<K, T>
+where
+    K: Eq + Hash,
+{
+    root: Rc<Node<K, T>>,
+}
+
+impl<K, T> SubtreeView<K, T>
+where
+    K: Eq + Hash,
+{
+    // Get the node this view is rooted at
+    pub fn root(&self) -> Rc<Node<K, T>> {
+        self.root.clone()
+    }
+
+    // Create a depth-first iterator over the subtree
+    pub fn iter_depth_first(&self) -> DepthFirstIterator<K, T> {
+        DepthFirstIterator {
+            stack: vec![self.root.clone()],
+        }
+    }
+
+    // Find a node by key within the subtree only. The view has no index of its own, so this
+    // scans the subtree
+    pub fn find(&self, key: &K) -> Option<Rc<Node<K, T>>> {
+        self.iter_depth_first().find(|node| *node.key() == *key)
+    }
+
+    // Count the nodes in the subtree, including its root
+    pub fn len(&self) -> usize {
+        self.iter_depth_first().count()
+    }
+
+    // A view always contains at least its root
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+// The traversal iterators below never hold a `RefCell` borrow between calls to `next`: when a
+// node is yielded, its children are cloned (as `Rc`s) onto the iterator's frontier. Callers can
+// therefore insert, remove or move nodes while iterating. The frontier is a snapshot, though:
+// children added under an already-yielded node are not visited, and nodes removed after they
+// were queued are still yielded (as detached handles).
+
+// Depth-First Iterator
+pub struct DepthFirstIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    stack: Vec<Rc<Node<K, T>>>,
+}
+
+impl<K, T> Iterator for DepthFirstIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    type Item = Rc<Node<K, T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(node) = self.stack.pop() {
+            for child in node.children.borrow().iter().rev() {
+                self.stack.push(child.clone());
+            }
+            Some(node)
+        } else {
+            None
+        }
+    }
+}
+
+// Post-Order Iterator (children before their parent, left to right)
+pub struct PostOrderIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    stack: Vec<(Rc<Node<K, T>>, bool)>, // `true` once the node's children have been queued
+}
+
+impl<K, T> Iterator for PostOrderIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    type Item = Rc<Node<K, T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, expanded) = self.stack.pop()?;
+            if expanded || node.is_leaf() {
+                return Some(node);
+            }
+
+            self.stack.push((node.clone(), true));
+            for child in node.children.borrow().iter().rev() {
+                self.stack.push((child.clone(), false));
+            }
+        }
+    }
+}
+
+// Breadth-First Iterator
+pub struct BreadthFirstIterator<K, T>
+where
+    K: Eq + Hash,
 {
     queue: VecDeque<Rc<Node<K, T>>>,
 }
@@ -360,6 +3448,35 @@ where
     }
 }
 
+// A node paired with its parent (`None` for the root)
+pub type NodeWithParent<K, T> = (Option<Rc<Node<K, T>>>, Rc<Node<K, T>>);
+
+// Parent Iterator (depth-first, yields `(parent, node)` pairs)
+pub struct ParentIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    stack: Vec<NodeWithParent<K, T>>,
+}
+
+impl<K, T> Iterator for ParentIterator<K, T>
+where
+    K: Eq + Hash,
+{
+    type Item = NodeWithParent<K, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((parent, node)) = self.stack.pop() {
+            for child in node.children.borrow().iter().rev() {
+                self.stack.push((Some(node.clone()), child.clone()));
+            }
+            Some((parent, node))
+        } else {
+            None
+        }
+    }
+}
+
 // Shortest Path Iterator
 pub struct ShortestPathIterator<K, T>
 where
@@ -385,3 +3502,390 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // r
+    // ├── a
+    // │   ├── a1
+    // │   │   └── x
+    // │   └── a2
+    // └── b
+    //     └── b1
+    fn sample() -> MultiIndexedTree<&'static str, i32> {
+        let tree = MultiIndexedTree::new("r", 0);
+        for (parent, key, value) in [
+            ("r", "a", 1),
+            ("r", "b", 2),
+            ("a", "a1", 3),
+            ("a", "a2", 4),
+            ("b", "b1", 5),
+            ("a1", "x", 6),
+        ] {
+            tree.insert(&parent, key, value).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn tree_level_wrappers_keep_the_tree_valid() {
+        let tree = sample();
+        tree.insert(&"b", "b2", 7).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.move_node(&"a2", &"b").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.child_keys(&"b").unwrap(), vec!["b1", "b2", "a2"]);
+
+        tree.move_node_at(&"x", &"b", 0).unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.child_keys(&"b").unwrap(), vec!["x", "b1", "b2", "a2"]);
+
+        tree.update(&"b1", 50).unwrap();
+        assert_eq!(*tree.find(&"b1").unwrap().value(), 50);
+        assert_eq!(tree.validate(), Ok(()));
+
+        let removed = tree.remove_subtree(&"b1").unwrap();
+        assert_eq!(removed.validate(), Ok(()));
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.flatten_into_parent(&"b").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.child_keys(&"r").unwrap(), vec!["a", "x", "b2", "a2"]);
+    }
+
+    #[test]
+    fn remove_subtree_keeps_sibling_order() {
+        let tree = sample();
+        tree.insert(&"r", "c", 7).unwrap();
+        tree.insert(&"r", "d", 8).unwrap();
+
+        let position = tree.index_path(&"a").unwrap()[0];
+        let removed = tree.remove_subtree(&"a").unwrap();
+        assert_eq!(tree.child_keys(&"r").unwrap(), vec!["b", "c", "d"]);
+        assert_eq!(tree.validate(), Ok(()));
+
+        tree.attach_subtree(&"r", removed, Some(position)).unwrap();
+        assert_eq!(tree.child_keys(&"r").unwrap(), vec!["a", "b", "c", "d"]);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[test]
+    fn mutating_inside_for_each_does_not_panic() {
+        let tree = sample();
+        tree.iter_depth_first().for_each(|node| {
+            let value = *node.value();
+            node.set_value(value + 1);
+            if *node.key() == "a1" {
+                tree.insert(&"b1", "y", 0).unwrap();
+                tree.move_node(&"a2", &"b").unwrap();
+                tree.remove_subtree(&"x").unwrap();
+            }
+        });
+
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.child_keys(&"b").unwrap(), vec!["b1", "a2"]);
+        assert_eq!(tree.child_keys(&"b1").unwrap(), vec!["y"]);
+        assert_eq!(*tree.find(&"b1").unwrap().value(), 6);
+    }
+
+    #[test]
+    fn rename_key_moves_every_index_entry() {
+        let tree = sample();
+        tree.enable_ordered_index();
+        tree.add_to_secondary_index("group".to_string(), "b");
+        tree.add_to_secondary_index("group".to_string(), "a1");
+        tree.add_to_secondary_index("group".to_string(), "x");
+
+        tree.rename_key(&"a1", "z").unwrap();
+
+        let node = tree.find(&"z").unwrap();
+        assert_eq!(*node.value(), 3);
+        assert_eq!(tree.child_keys(&"z").unwrap(), vec!["x"]);
+        assert!(tree.find(&"a1").is_none());
+
+        let tagged: Vec<_> = tree
+            .find_by_secondary_index("group")
+            .unwrap()
+            .iter()
+            .map(|node| *node.key())
+            .collect();
+        assert_eq!(tagged, vec!["b", "z", "x"]);
+
+        let ranged: Vec<_> = tree
+            .range(&"y", &"zz")
+            .iter()
+            .map(|node| *node.key())
+            .collect();
+        assert_eq!(ranged, vec!["z"]);
+        assert!(tree.range(&"a1", &"a2").is_empty());
+        let ordered = tree.ordered_index.borrow();
+        assert!(ordered.as_ref().unwrap().contains_key(&"z"));
+        assert!(!ordered.as_ref().unwrap().contains_key(&"a1"));
+        drop(ordered);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[test]
+    fn reindex_children_repairs_corrupted_positions() {
+        let tree = sample();
+        *tree.find(&"a2").unwrap().index.borrow_mut() = 5;
+        assert_eq!(tree.validate(), Err(TreeError::Inconsistent("a2")));
+
+        tree.reindex_children();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(*tree.find(&"a2").unwrap().index.borrow(), 1);
+    }
+
+    #[test]
+    fn remove_from_secondary_index_keeps_order() {
+        let tree = sample();
+        for key in ["x", "a", "b", "a2", "r"] {
+            tree.add_to_secondary_index("group".to_string(), key);
+        }
+
+        assert!(tree.remove_from_secondary_index("group", &"b"));
+        assert!(!tree.remove_from_secondary_index("group", &"b"));
+        assert!(!tree.remove_from_secondary_index("missing", &"a"));
+
+        let tagged: Vec<_> = tree
+            .find_by_secondary_index("group")
+            .unwrap()
+            .iter()
+            .map(|node| *node.key())
+            .collect();
+        assert_eq!(tagged, vec!["x", "a", "a2", "r"]);
+    }
+
+    #[test]
+    fn collapse_if_keeps_children_in_order() {
+        let tree = sample();
+        tree.insert(&"a", "a3", 7).unwrap();
+        tree.insert(&"a2", "p", 8).unwrap();
+        tree.insert(&"a2", "q", 9).unwrap();
+
+        tree.collapse_if(|node| ["a", "a2"].contains(&*node.key()));
+
+        assert_eq!(
+            tree.child_keys(&"r").unwrap(),
+            vec!["a1", "p", "q", "a3", "b"]
+        );
+        for (position, child) in tree.root().children().iter().enumerate() {
+            assert_eq!(*child.index.borrow(), position);
+        }
+        assert_eq!(tree.child_keys(&"a1").unwrap(), vec!["x"]);
+        assert!(tree.find(&"a").is_none() && tree.find(&"a2").is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[test]
+    fn compact_secondary_index_drops_empty_buckets() {
+        let tree = sample();
+        tree.add_to_secondary_index("gone".to_string(), "x");
+        tree.add_to_secondary_index("mixed".to_string(), "x");
+        tree.add_to_secondary_index("mixed".to_string(), "b1");
+        tree.remove_subtree(&"a1").unwrap();
+
+        tree.compact_secondary_index();
+
+        assert!(tree.find_by_secondary_index("gone").is_none());
+        assert!(!tree.secondary_index.borrow().contains_key("gone"));
+        assert_eq!(tree.secondary_index.borrow()["mixed"], vec!["b1"]);
+    }
+
+    #[test]
+    fn renamed_node_keeps_its_lru_position() {
+        let tree = MultiIndexedTree::with_capacity_eviction("r", 0, 3);
+        tree.insert(&"r", "a", 1).unwrap();
+        tree.insert(&"r", "b", 2).unwrap();
+        tree.find(&"a");
+        tree.rename_key(&"a", "z").unwrap();
+
+        tree.insert(&"r", "c", 3).unwrap();
+        assert!(tree.find(&"b").is_none());
+        assert!(tree.find(&"z").is_some());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[test]
+    fn grafts_respect_node_capacity() {
+        let tree = MultiIndexedTree::with_capacity_eviction("r", 0, 3);
+        tree.insert(&"r", "a", 1).unwrap();
+
+        let big = MultiIndexedTree::new("s", 0);
+        big.insert(&"s", "s1", 0).unwrap();
+        assert_eq!(
+            tree.attach_subtree(&"r", big.deep_clone(), None),
+            Err(TreeError::CapacityExceeded("s"))
+        );
+        assert_eq!(tree.leaf_count() + tree.internal_node_count(), 2);
+
+        // Replacing "a" frees one slot, which is exactly enough
+        tree.replace_subtree(&"a", big).unwrap();
+        assert_eq!(tree.leaf_count() + tree.internal_node_count(), 3);
+        assert_eq!(tree.validate(), Ok(()));
+
+        let extra = MultiIndexedTree::new("t", 0);
+        assert_eq!(
+            tree.attach_subtree(&"r", extra, None),
+            Err(TreeError::CapacityExceeded("t"))
+        );
+    }
+
+    #[test]
+    fn internal_lookups_do_not_count_as_accesses() {
+        let tree = MultiIndexedTree::with_capacity_eviction("r", 0, 4);
+        tree.insert(&"r", "a", 1).unwrap();
+        tree.insert(&"r", "b", 2).unwrap();
+        tree.insert(&"r", "c", 3).unwrap();
+        tree.add_to_secondary_index("group".to_string(), "a");
+
+        // None of these should refresh "a", which stays the least recently used
+        assert_eq!(tree.distance(&"a", &"b"), Some(2));
+        assert!(tree.are_siblings(&"a", &"c"));
+        assert_eq!(*tree.lca(&"a", &"b").unwrap().key(), "r");
+        assert_eq!(tree.find_by_secondary_index("group").unwrap().len(), 1);
+        assert_eq!(tree.all_tagged_nodes().len(), 1);
+
+        tree.insert(&"r", "d", 4).unwrap();
+        assert!(tree.find(&"a").is_none());
+
+        // An explicit `update` does count, so "b" now outlives "c"
+        tree.update(&"b", 20).unwrap();
+        tree.insert(&"r", "e", 5).unwrap();
+        assert!(tree.find(&"c").is_none());
+        assert!(tree.find(&"b").is_some());
+    }
+
+    #[test]
+    fn from_bytes_rejects_duplicate_keys() {
+        let tree = MultiIndexedTree::new(b"r".to_vec(), b"0".to_vec());
+        tree.insert(&b"r".to_vec(), b"a".to_vec(), b"1".to_vec())
+            .unwrap();
+        tree.insert(&b"r".to_vec(), b"b".to_vec(), b"2".to_vec())
+            .unwrap();
+        let mut bytes = tree.to_bytes();
+        assert!(MultiIndexedTree::<Vec<u8>, Vec<u8>>::from_bytes(&bytes).is_ok());
+
+        // Rename the last record's key from "b" to "a"
+        let chunk = [1, 0, 0, 0, 0, 0, 0, 0, b'b'];
+        let at = bytes
+            .windows(chunk.len())
+            .rposition(|window| window == chunk)
+            .unwrap();
+        bytes[at + chunk.len() - 1] = b'a';
+
+        let err = MultiIndexedTree::<Vec<u8>, Vec<u8>>::from_bytes(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_from_rejects_duplicate_keys() {
+        let input = "r\tv\nr\ta\tx\nr\ta\ty\n";
+        let err = MultiIndexedTree::<String, String>::read_from(input.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn remove_node_subtree_takes_a_find_result() {
+        let tree = sample();
+        let node = tree.find(&"a").unwrap();
+
+        let removed = tree.remove_node_subtree(&node).unwrap();
+        assert_eq!(removed.validate(), Ok(()));
+        assert_eq!(tree.validate(), Ok(()));
+        assert!(tree.find(&"a1").is_none());
+        assert_eq!(tree.child_keys(&"r").unwrap(), vec!["b"]);
+
+        // The handle now belongs to `removed`, and a copy was never indexed by `tree`
+        assert_eq!(
+            tree.remove_node_subtree(&node).err(),
+            Some(TreeError::KeyNotFound("a"))
+        );
+        let copy = sample().find(&"b").unwrap();
+        assert_eq!(
+            tree.remove_node_subtree(&copy).err(),
+            Some(TreeError::KeyNotFound("b"))
+        );
+        assert_eq!(
+            tree.remove_node_subtree(&tree.root()).err(),
+            Some(TreeError::RootNode("r"))
+        );
+    }
+
+    #[test]
+    fn remove_keeps_sibling_order() {
+        let tree = sample();
+        tree.enable_ordered_index();
+        tree.insert(&"r", "c", 7).unwrap();
+        tree.insert(&"r", "d", 8).unwrap();
+        assert_eq!(tree.index_path(&"d"), Some(vec![3]));
+
+        tree.remove(&"b").unwrap();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.child_keys(&"r").unwrap(), vec!["a", "c", "d"]);
+        assert_eq!(tree.index_path(&"d"), Some(vec![2]));
+        assert_eq!(tree.next_sibling(&"a").unwrap().key().clone(), "c");
+        assert!(tree.find(&"b1").is_none());
+    }
+
+    #[test]
+    fn split_at_partitions_the_secondary_index() {
+        let tree = sample();
+        tree.add_to_secondary_index("group".to_string(), "x");
+        tree.add_to_secondary_index("group".to_string(), "b1");
+        tree.add_to_secondary_index("group".to_string(), "a1");
+        tree.add_to_secondary_index("inner".to_string(), "a2");
+
+        let (detached, remaining) = tree.split_at(&"a").unwrap();
+        assert_eq!(detached.secondary_index.borrow()["group"], vec!["x", "a1"]);
+        assert_eq!(detached.secondary_index.borrow()["inner"], vec!["a2"]);
+        for part in [&tree, &remaining] {
+            assert_eq!(part.secondary_index.borrow()["group"], vec!["b1"]);
+            assert!(part.find_by_secondary_index("inner").is_none());
+        }
+
+        // The tags travel back with the subtree
+        tree.attach_subtree(&"r", detached, Some(0)).unwrap();
+        assert_eq!(
+            tree.secondary_index.borrow()["group"],
+            vec!["b1", "x", "a1"]
+        );
+        assert_eq!(tree.secondary_index.borrow()["inner"], vec!["a2"]);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn strong_count_ignores_the_ordered_index() {
+        let tree = sample();
+        assert_eq!(tree.strong_count(&"b1"), Some(2));
+
+        tree.enable_ordered_index();
+        assert_eq!(tree.strong_count(&"b1"), Some(2));
+        assert_eq!(tree.strong_count(&"r"), Some(2));
+        assert!(tree.audit_refcounts().values().all(|&count| count == 2));
+
+        let handle = tree.find(&"b1").unwrap();
+        assert_eq!(tree.strong_count(&"b1"), Some(3));
+        drop(handle);
+        assert_eq!(tree.strong_count(&"b1"), Some(2));
+    }
+
+    #[test]
+    fn detach_children_keeps_child_order() {
+        let tree = sample();
+        tree.enable_ordered_index();
+        tree.insert(&"a", "a3", 7).unwrap();
+
+        let detached = tree.detach_children(&"a").unwrap();
+        let roots: Vec<_> = detached.iter().map(|tree| *tree.root().key()).collect();
+        assert_eq!(roots, vec!["a1", "a2", "a3"]);
+        assert!(detached.iter().all(|tree| tree.validate().is_ok()));
+        assert!(tree.find(&"a").unwrap().is_leaf());
+        assert!(tree.find(&"x").is_none());
+        assert_eq!(tree.validate(), Ok(()));
+    }
+}